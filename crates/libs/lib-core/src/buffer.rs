@@ -347,7 +347,7 @@ mod tests {
         buf.commit(4);
         buf.write_str("1234567");
         buf.seek(4);
-        assert_eq!(buf.write_front("bbbb".as_bytes()), true);
+        assert!(buf.write_front("bbbb".as_bytes()));
 
         let r = buf.read(8);
         println!("{}", String::from_utf8_lossy(r.unwrap().as_ref()));
@@ -359,19 +359,19 @@ mod tests {
     #[test]
     fn test_buffer_2() {
         let mut buf = Buffer::with_capacity(128);
-        assert!(buf.len() == 0);
+        assert!(buf.is_empty());
         let n: i32 = 0;
         buf.write_slice(n.to_le_bytes().as_ref());
         assert_eq!(buf.len(), 4);
         assert!(buf.read(4).unwrap() == n.to_le_bytes().as_ref());
-        assert!(buf.len() == 0);
+        assert!(buf.is_empty());
         assert!(buf.read(4).is_none());
     }
 
     #[test]
     fn test_buffer_3() {
         let mut buf = Buffer::with_capacity(32);
-        assert!(buf.len() == 0);
+        assert!(buf.is_empty());
 
         for _ in 0..100 {
             buf.write_slice(vec![0; 17].as_ref());
@@ -4,6 +4,8 @@ use std::env;
 
 fn main() {
     println!("cargo:rerun-if-changed=lualib-src");
+    // poll time metrics are only compiled with RUSTFLAGS="--cfg tokio_unstable"
+    println!("cargo::rustc-check-cfg=cfg(tokio_unstable)");
 
     if cfg!(target_os = "windows") {
         let profile = env::var("PROFILE").unwrap_or_else(|_| "release".to_string());
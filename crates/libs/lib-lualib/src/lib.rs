@@ -46,6 +46,24 @@ pub fn moon_send_bytes(protocol_type: u8, owner: u32, session: i64, data: &[u8])
     }
 }
 
+lazy_static::lazy_static! {
+    /// Set once by `shutdown_databases`, every database handler selects on it
+    static ref DB_SHUTDOWN: tokio::sync::watch::Sender<bool> = tokio::sync::watch::Sender::new(false);
+}
+
+/// Signals every database handler to stop taking requests, drain its queue and close its pool
+pub fn shutdown_databases() {
+    DB_SHUTDOWN.send_replace(true);
+}
+
+pub fn db_shutdown_receiver() -> tokio::sync::watch::Receiver<bool> {
+    DB_SHUTDOWN.subscribe()
+}
+
+pub fn db_shutting_down() -> bool {
+    *DB_SHUTDOWN.borrow()
+}
+
 pub const PTYPE_ERROR: u8 = 4;
 pub const PTYPE_LOG: u8 = 13;
 
@@ -2,7 +2,7 @@ use lib_core::context::CONTEXT;
 use lib_lua::{
     self, cstr,
     ffi::{self},
-    laux::{self, LuaState}, lreg, lreg_null, luaL_newlib,
+    laux::{self, LuaState, LuaTable}, lreg, lreg_null, luaL_newlib,
 };
 
 extern "C-unwind" fn num_alive_tasks(state: LuaState) -> i32 {
@@ -13,10 +13,68 @@ extern "C-unwind" fn num_alive_tasks(state: LuaState) -> i32 {
     1
 }
 
+/// Scheduler metrics for spotting starvation, e.g. DB decode work running long between yields.
+/// Per worker: busy_ms and park_count; mean_poll_time_us only in builds with `--cfg tokio_unstable`.
+extern "C-unwind" fn metrics(state: LuaState) -> i32 {
+    let metrics = CONTEXT.tokio_runtime.metrics();
+    let table = LuaTable::new(state, 0, 5);
+    table.insert("num_workers", metrics.num_workers() as i64);
+    table.insert("num_alive_tasks", metrics.num_alive_tasks() as i64);
+    table.insert("global_queue_depth", metrics.global_queue_depth() as i64);
+    table.insert("poll_time_available", cfg!(tokio_unstable));
+
+    laux::lua_push(state, "workers");
+    let workers = LuaTable::new(state, metrics.num_workers(), 0);
+    for worker in 0..metrics.num_workers() {
+        let entry = LuaTable::new(state, 0, 3);
+        entry.insert(
+            "busy_ms",
+            metrics.worker_total_busy_duration(worker).as_secs_f64() * 1000.0,
+        );
+        entry.insert("park_count", metrics.worker_park_count(worker) as i64);
+        #[cfg(tokio_unstable)]
+        entry.insert(
+            "mean_poll_time_us",
+            metrics.worker_mean_poll_time(worker).as_secs_f64() * 1_000_000.0,
+        );
+        workers.rawseti(worker + 1);
+    }
+    table.insert_from_stack();
+    1
+}
+
+/// Backends compiled into this build, with the major version of the driver crate
+extern "C-unwind" fn features(state: LuaState) -> i32 {
+    let table = LuaTable::new(state, 0, 12);
+    table.insert("version", env!("CARGO_PKG_VERSION"));
+    table.insert("sqlx", cfg!(feature = "sqlx"));
+    if cfg!(feature = "sqlx") {
+        table.insert("sqlx_version", "0.8");
+    }
+    table.insert("tiberius", cfg!(feature = "tiberius"));
+    if cfg!(feature = "tiberius") {
+        table.insert("tiberius_version", "0.12");
+    }
+    table.insert("mongodb", cfg!(feature = "mongodb"));
+    if cfg!(feature = "mongodb") {
+        table.insert("mongodb_version", "3.2");
+    }
+    table.insert("excel", cfg!(feature = "excel"));
+    table.insert("http", cfg!(feature = "http"));
+    table.insert("websocket", cfg!(feature = "websocket"));
+    table.insert("json", cfg!(feature = "json"));
+    1
+}
+
 #[unsafe(no_mangle)]
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C-unwind" fn luaopen_rust_runtime(state: LuaState) -> i32 {
-    let l = [lreg!("num_alive_tasks", num_alive_tasks), lreg_null!()];
+    let l = [
+        lreg!("num_alive_tasks", num_alive_tasks),
+        lreg!("metrics", metrics),
+        lreg!("features", features),
+        lreg_null!(),
+    ];
     luaL_newlib!(state, l);
     1
 }
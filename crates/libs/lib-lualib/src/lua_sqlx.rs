@@ -1,34 +1,568 @@
-use std::sync::{Arc, atomic::AtomicI64};
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize},
+};
+use std::time::{Duration, Instant};
 
+use chrono::TimeZone;
+use chrono_tz::Tz;
 use dashmap::DashMap;
+use encoding_rs::Encoding;
+use futures::TryStreamExt;
 use lazy_static::lazy_static;
+use rust_decimal::Decimal;
 use sqlx::types::Uuid;
 use sqlx::{
-    Column, ColumnIndex, Database, MySql, MySqlPool, PgPool, Postgres, Row, Sqlite, SqlitePool,
-    TypeInfo, ValueRef,
+    Column, ColumnIndex, Connection, Database, Either, IntoArguments, MySql, MySqlPool, PgPool,
+    Postgres, Row, Sqlite, SqlitePool, TypeInfo, ValueRef,
+    encode::IsNull,
+    error::{BoxDynError, DatabaseError, ErrorKind},
     migrate::MigrateDatabase,
-    mysql::MySqlRow,
-    postgres::{PgPoolOptions, PgRow},
-    sqlite::SqliteRow,
+    mysql::{MySqlConnectOptions, MySqlDatabaseError, MySqlRow},
+    pool::{PoolConnection, PoolOptions},
+    postgres::{
+        PgArgumentBuffer, PgConnectOptions, PgPoolOptions, PgRow, PgTypeInfo, PgTypeKind,
+        types::{Oid, PgInterval, PgMoney},
+    },
+    query::Query,
+    sqlite::{SqliteConnectOptions, SqliteError, SqliteJournalMode, SqliteRow, SqliteValueRef},
     types::chrono::{NaiveDate, NaiveDateTime, NaiveTime},
 };
-use tokio::{sync::mpsc, time::timeout};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
 
 use lib_core::context::CONTEXT;
 use lib_lua::{
     self, cstr, ffi, laux,
-    laux::{LuaArgs, LuaNil, LuaState, LuaTable, LuaValue, lua_into_userdata},
+    laux::{LuaArgs, LuaNil, LuaState, LuaTable, LuaType, LuaValue, lua_into_userdata},
     lreg, lreg_null, luaL_newlib, push_lua_table,
 };
 
 use crate::lua_json::{JsonOptions, encode_table};
-use crate::{LOG_LEVEL_ERROR, LOG_LEVEL_INFO, moon_log, moon_send};
+use crate::{
+    LOG_LEVEL_DEBUG, LOG_LEVEL_ERROR, LOG_LEVEL_INFO, LOG_LEVEL_WARN, db_shutdown_receiver,
+    db_shutting_down, moon_log, moon_send, shutdown_databases,
+};
 
 lazy_static! {
     static ref DATABASE_CONNECTIONSS: DashMap<String, DatabaseConnection> = DashMap::new();
+    static ref PENDING_CONNECTS: DashMap<String, oneshot::Sender<()>> = DashMap::new();
+}
+
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+const SQLITE_BUSY_MAX_RETRIES: i32 = 5;
+const VALIDATION_MAX_RETRIES: u32 = 3;
+
+#[derive(Clone)]
+struct ConnectOptions {
+    busy_timeout: Option<u64>,
+    wal: bool,
+    rate_limit_qps: Option<u32>,
+    charset: Option<String>,
+    collation: Option<String>,
+    log_pool_events: bool,
+    raise_errors: bool,
+    hosts: Vec<String>,
+    validation_query: Option<String>,
+    session_setup: Vec<String>,
+    prepare: Vec<String>,
+    schema: Option<String>,
+    saturation_warn: Option<f64>,
+    singleflight: bool,
+    max_inflight: Option<i64>,
+    wrap_in_transaction: bool,
+    slow_transaction_ms: Option<u64>,
+    max_sql_bytes: Option<usize>,
+    no_json_autodetect: bool,
+    ssl_cert: Option<String>,
+    ssl_key: Option<String>,
+    decode: DecodeOptions,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            busy_timeout: None,
+            wal: false,
+            rate_limit_qps: None,
+            charset: None,
+            collation: None,
+            log_pool_events: false,
+            raise_errors: false,
+            hosts: Vec::new(),
+            validation_query: Some("SELECT 1".to_string()),
+            session_setup: Vec::new(),
+            prepare: Vec::new(),
+            schema: None,
+            saturation_warn: None,
+            singleflight: false,
+            max_inflight: None,
+            wrap_in_transaction: false,
+            slow_transaction_ms: None,
+            max_sql_bytes: None,
+            no_json_autodetect: false,
+            ssl_cert: None,
+            ssl_key: None,
+            decode: DecodeOptions::default(),
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+struct DecodeOptions {
+    display_tz: Option<Tz>,
+    empty_as_nil: bool,
+    relations: DashMap<u32, String>,
+}
+
+impl DecodeOptions {
+    fn relation_name(&self, oid: u32) -> String {
+        self.relations
+            .get(&oid)
+            .map(|name| name.clone())
+            .unwrap_or_else(|| oid.to_string())
+    }
+}
+
+/// Postgres sends regclass values as OIDs, their names are looked up on the same connection
+async fn resolve_relations<'c, E>(
+    executor: E,
+    rows: &[PgRow],
+    decode: &DecodeOptions,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = Postgres>,
+{
+    let Some(first) = rows.first() else {
+        return Ok(());
+    };
+    let columns: Vec<usize> = first
+        .columns()
+        .iter()
+        .filter(|column| column.type_info().name() == "regclass")
+        .map(Column::ordinal)
+        .collect();
+    if columns.is_empty() {
+        return Ok(());
+    }
+
+    let mut oids: Vec<i64> = rows
+        .iter()
+        .flat_map(|row| {
+            columns
+                .iter()
+                .filter_map(move |&index| row.try_get_unchecked::<Option<Oid>, _>(index).ok())
+        })
+        .flatten()
+        .filter(|oid| !decode.relations.contains_key(&oid.0))
+        .map(|oid| oid.0 as i64)
+        .collect();
+    if oids.is_empty() {
+        return Ok(());
+    }
+    oids.sort_unstable();
+    oids.dedup();
+
+    // a dropped relation prints as its bare OID instead of failing
+    let names: Vec<(i64, String)> =
+        sqlx::query_as("SELECT o, o::oid::regclass::text FROM unnest($1::int8[]) AS t(o)")
+            .bind(oids)
+            .fetch_all(executor)
+            .await?;
+    for (oid, name) in names {
+        decode.relations.insert(oid as u32, name);
+    }
+    Ok(())
+}
+
+impl ConnectOptions {
+    fn from_lua(state: LuaState, index: i32) -> Result<Self, String> {
+        if laux::lua_type(state, index) != LuaType::Table {
+            return Ok(Self::default());
+        }
+
+        let display_tz = match laux::opt_field::<&str>(state, index, "display_tz") {
+            Some(name) => Some(
+                name.parse::<Tz>()
+                    .map_err(|err| format!("invalid display_tz '{}': {}", name, err))?,
+            ),
+            None => None,
+        };
+
+        let table = LuaTable::from_stack(state, index);
+        let hosts = string_array(&table, "hosts", "'host[:port]'")?;
+        let session_setup = string_array(&table, "session_setup", "SQL statements")?;
+        let prepare = string_array(&table, "prepare", "SQL statements")?;
+        let ssl_cert = laux::opt_field::<String>(state, index, "ssl_cert");
+        let ssl_key = laux::opt_field::<String>(state, index, "ssl_key");
+        if ssl_cert.is_some() != ssl_key.is_some() {
+            return Err("ssl_cert and ssl_key must be set together".to_string());
+        }
+
+        Ok(Self {
+            busy_timeout: laux::opt_field(state, index, "busy_timeout"),
+            wal: laux::opt_field(state, index, "wal").unwrap_or(false),
+            rate_limit_qps: laux::opt_field::<u32>(state, index, "rate_limit_qps")
+                .filter(|qps| *qps > 0),
+            charset: laux::opt_field(state, index, "charset"),
+            collation: laux::opt_field(state, index, "collation"),
+            log_pool_events: laux::opt_field(state, index, "log_pool_events").unwrap_or(false),
+            raise_errors: laux::opt_field(state, index, "raise_errors").unwrap_or(false),
+            hosts,
+            validation_query: match laux::opt_field::<String>(state, index, "validation_query") {
+                Some(sql) if sql.is_empty() => None,
+                Some(sql) => Some(sql),
+                None => Some("SELECT 1".to_string()),
+            },
+            session_setup,
+            prepare,
+            schema: laux::opt_field::<String>(state, index, "schema")
+                .or_else(|| laux::opt_field(state, index, "search_path"))
+                .filter(|schema| !schema.is_empty()),
+            saturation_warn: laux::opt_field::<f64>(state, index, "saturation_warn")
+                .filter(|ratio| *ratio > 0.0),
+            singleflight: laux::opt_field(state, index, "singleflight").unwrap_or(false),
+            max_inflight: laux::opt_field::<i64>(state, index, "max_inflight")
+                .filter(|max| *max > 0),
+            wrap_in_transaction: laux::opt_field(state, index, "wrap_in_transaction")
+                .or_else(|| {
+                    laux::opt_field::<bool>(state, index, "autocommit")
+                        .map(|autocommit| !autocommit)
+                })
+                .unwrap_or(false),
+            slow_transaction_ms: laux::opt_field::<u64>(state, index, "slow_transaction_ms")
+                .filter(|ms| *ms > 0),
+            max_sql_bytes: laux::opt_field::<usize>(state, index, "max_sql_bytes")
+                .filter(|max| *max > 0),
+            no_json_autodetect: laux::opt_field(state, index, "no_json_autodetect")
+                .unwrap_or(false),
+            ssl_cert,
+            ssl_key,
+            decode: DecodeOptions {
+                display_tz,
+                empty_as_nil: laux::opt_field(state, index, "empty_as_nil").unwrap_or(false),
+                relations: DashMap::new(),
+            },
+        })
+    }
+}
+
+fn string_array(table: &LuaTable, field: &str, what: &str) -> Result<Vec<String>, String> {
+    let mut items = Vec::new();
+    match &table.rawget(field).value {
+        LuaValue::Table(list) => {
+            for item in list.array_iter() {
+                match item {
+                    LuaValue::String(item) => {
+                        items.push(String::from_utf8_lossy(item).into_owned())
+                    }
+                    _ => return Err(format!("{} expects an array of {}", field, what)),
+                }
+            }
+        }
+        LuaValue::Nil => {}
+        _ => return Err(format!("{} expects an array of {}", field, what)),
+    }
+    Ok(items)
+}
+
+/// Splits the rows at every result set, a MySQL `CALL` also ends with an empty status one
+async fn fetch_result_sets<'e, 'c: 'e, 'q: 'e, DB, E>(
+    executor: E,
+    query: Query<'q, DB, <DB as Database>::Arguments<'q>>,
+    call: bool,
+) -> Result<Vec<Vec<<DB as Database>::Row>>, sqlx::Error>
+where
+    DB: Database,
+    E: 'e + sqlx::Executor<'c, Database = DB>,
+    <DB as Database>::Arguments<'q>: IntoArguments<'q, DB>,
+{
+    let mut sets = Vec::new();
+    let mut rows = Vec::new();
+    let mut results = executor.fetch_many(query);
+    while let Some(result) = results.try_next().await? {
+        match result {
+            Either::Left(_) => sets.push(std::mem::take(&mut rows)),
+            Either::Right(row) => rows.push(row),
+        }
+    }
+    if !rows.is_empty() {
+        sets.push(rows);
+    } else if call {
+        sets.pop();
+    }
+    Ok(sets)
+}
+
+struct AcquireWaiter<'a>(&'a AtomicI64);
+
+impl<'a> AcquireWaiter<'a> {
+    fn new(waiters: &'a AtomicI64) -> Self {
+        waiters.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+        Self(waiters)
+    }
+}
+
+impl Drop for AcquireWaiter<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
+    }
+}
+
+async fn acquire<DB: Database>(
+    pool: &sqlx::Pool<DB>,
+    waiters: &AtomicI64,
+) -> Result<PoolConnection<DB>, sqlx::Error> {
+    let _waiter = AcquireWaiter::new(waiters);
+    pool.acquire().await
+}
+
+/// Kept per connection, another database or search_path may resolve a name to another type
+#[derive(Clone, Default)]
+struct CompositeTypes(Arc<DashMap<String, usize>>);
+
+impl CompositeTypes {
+    /// A cached count that doesn't match is looked up again, `ALTER TYPE` may have changed it
+    async fn check(
+        &self,
+        pool: &DatabasePool,
+        querys: &[DatabaseQuery],
+    ) -> Result<(), sqlx::Error> {
+        let DatabasePool::Postgres(pool) = pool else {
+            return Ok(());
+        };
+        for bind in querys.iter().flat_map(|query| query.binds.iter()) {
+            let QueryParams::Record {
+                type_name, fields, ..
+            } = bind
+            else {
+                continue;
+            };
+            let cached = self.0.get(type_name).map(|expected| *expected);
+            let mut expected = match cached {
+                Some(expected) => expected,
+                None => self.refresh(pool, type_name).await?,
+            };
+            if cached.is_some() && expected != *fields {
+                expected = self.refresh(pool, type_name).await?;
+            }
+            if expected == 0 {
+                return Err(sqlx::Error::Encode(
+                    format!("'{}' is not a composite type", type_name).into(),
+                ));
+            }
+            if expected != *fields {
+                return Err(sqlx::Error::Encode(
+                    format!(
+                        "record for '{}' has {} fields, the type has {}",
+                        type_name, fields, expected
+                    )
+                    .into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn refresh(&self, pool: &PgPool, type_name: &str) -> Result<usize, sqlx::Error> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM pg_attribute WHERE attrelid = \
+             (SELECT typrelid FROM pg_type WHERE oid = $1::regtype) \
+             AND attnum > 0 AND NOT attisdropped",
+        )
+        .bind(type_name)
+        .fetch_one(pool)
+        .await?;
+        self.0.insert(type_name.to_string(), count as usize);
+        Ok(count as usize)
+    }
+}
+
+async fn fetch_with_count<'e, 'c: 'e, 'q: 'e, DB, E>(
+    executor: E,
+    query: Query<'q, DB, <DB as Database>::Arguments<'q>>,
+) -> Result<(Vec<<DB as Database>::Row>, <DB as Database>::QueryResult), sqlx::Error>
+where
+    DB: Database,
+    E: 'e + sqlx::Executor<'c, Database = DB>,
+    <DB as Database>::Arguments<'q>: IntoArguments<'q, DB>,
+{
+    let mut rows = Vec::new();
+    let mut done = <DB as Database>::QueryResult::default();
+    let mut results = executor.fetch_many(query);
+    while let Some(result) = results.try_next().await? {
+        match result {
+            Either::Left(result) => done.extend(Some(result)),
+            Either::Right(row) => rows.push(row),
+        }
+    }
+    Ok((rows, done))
+}
+
+/// Keeps at most `max_rows + 1` rows, the extra one marks the truncation. The server still
+/// sends every row, the connection drains the rest before its next use
+async fn fetch_rows<'e, 'c: 'e, 'q: 'e, DB, E>(
+    executor: E,
+    query: Query<'q, DB, <DB as Database>::Arguments<'q>>,
+    max_rows: Option<usize>,
+) -> Result<Vec<<DB as Database>::Row>, sqlx::Error>
+where
+    DB: Database,
+    E: 'e + sqlx::Executor<'c, Database = DB>,
+    <DB as Database>::Arguments<'q>: IntoArguments<'q, DB>,
+{
+    let Some(max_rows) = max_rows else {
+        return query.fetch_all(executor).await;
+    };
+    let mut rows = Vec::new();
+    let mut stream = query.fetch(executor);
+    while rows.len() <= max_rows
+        && let Some(row) = stream.try_next().await?
+    {
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+fn truncate_rows<R>(rows: &mut Vec<R>, max_rows: Option<usize>) -> bool {
+    match max_rows {
+        Some(max_rows) if rows.len() > max_rows => {
+            rows.truncate(max_rows);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn with_pool_hooks<DB: Database>(
+    pool_options: PoolOptions<DB>,
+    label: &str,
+    owner: u32,
+    options: &ConnectOptions,
+) -> PoolOptions<DB>
+where
+    for<'c> &'c mut <DB as Database>::Connection: sqlx::Executor<'c, Database = DB>,
+{
+    let log_events = options.log_pool_events;
+    if !log_events && options.session_setup.is_empty() && options.prepare.is_empty() {
+        return pool_options;
+    }
+
+    let session_setup = Arc::new(options.session_setup.clone());
+    let prepare = Arc::new(options.prepare.clone());
+    let on_connect = label.to_string();
+    let pool_options = pool_options.after_connect(move |conn, _| {
+        let session_setup = session_setup.clone();
+        let prepare = prepare.clone();
+        let label = on_connect.clone();
+        Box::pin(async move {
+            if log_events {
+                moon_log(
+                    owner,
+                    LOG_LEVEL_DEBUG,
+                    format!("Database '{}' pool: connection established", label),
+                );
+            }
+            for sql in session_setup.iter() {
+                if let Err(err) = sqlx::Executor::execute(&mut *conn, sql.as_str()).await {
+                    moon_log(
+                        owner,
+                        LOG_LEVEL_ERROR,
+                        format!(
+                            "Database '{}' session setup '{}' failed: {}",
+                            label, sql, err
+                        ),
+                    );
+                    return Err(err);
+                }
+            }
+            // a statement that fails to prepare is only logged, it fails again when run
+            if !prepare.is_empty() {
+                let mut prepared = 0;
+                for sql in prepare.iter() {
+                    match sqlx::Executor::prepare(&mut *conn, sql.as_str()).await {
+                        Ok(_) => prepared += 1,
+                        Err(err) => moon_log(
+                            owner,
+                            LOG_LEVEL_WARN,
+                            format!(
+                                "Database '{}' warmup prepare '{}' failed: {}",
+                                label, sql, err
+                            ),
+                        ),
+                    }
+                }
+                moon_log(
+                    owner,
+                    LOG_LEVEL_INFO,
+                    format!(
+                        "Database '{}' warmup prepared {}/{} statements",
+                        label,
+                        prepared,
+                        prepare.len()
+                    ),
+                );
+            }
+            Ok(())
+        })
+    });
+    if !log_events {
+        return pool_options;
+    }
+
+    let on_acquire = label.to_string();
+    let on_release = label.to_string();
+    pool_options
+        .before_acquire(move |_, meta| {
+            moon_log(
+                owner,
+                LOG_LEVEL_DEBUG,
+                format!(
+                    "Database '{}' pool: acquire connection (age {:?}, idle {:?})",
+                    on_acquire, meta.age, meta.idle_for
+                ),
+            );
+            Box::pin(async { Ok(true) })
+        })
+        .after_release(move |_, meta| {
+            moon_log(
+                owner,
+                LOG_LEVEL_DEBUG,
+                format!(
+                    "Database '{}' pool: release connection (age {:?})",
+                    on_release, meta.age
+                ),
+            );
+            Box::pin(async { Ok(true) })
+        })
 }
 
+/// Read up front so a bad file fails the connect naming it, not the TLS handshake
+async fn read_client_cert(
+    options: &ConnectOptions,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>, sqlx::Error> {
+    async fn read_pem(option: &str, path: &str) -> Result<Vec<u8>, sqlx::Error> {
+        tokio::fs::read(path).await.map_err(|err| {
+            sqlx::Error::Configuration(format!("{} '{}': {}", option, path, err).into())
+        })
+    }
+
+    let (Some(cert), Some(key)) = (&options.ssl_cert, &options.ssl_key) else {
+        return Ok(None);
+    };
+    Ok(Some((
+        read_pem("ssl_cert", cert).await?,
+        read_pem("ssl_key", key).await?,
+    )))
+}
+
+#[derive(Clone)]
 enum DatabasePool {
     MySql(MySqlPool),
     Postgres(PgPool),
@@ -36,7 +570,13 @@ enum DatabasePool {
 }
 
 impl DatabasePool {
-    async fn connect(database_url: &str, timeout_duration: Duration) -> Result<Self, sqlx::Error> {
+    async fn connect(
+        database_url: &str,
+        timeout_duration: Duration,
+        options: &ConnectOptions,
+        label: &str,
+        owner: u32,
+    ) -> Result<Self, sqlx::Error> {
         async fn connect_with_timeout<F, T>(
             timeout_duration: Duration,
             connect_future: F,
@@ -52,25 +592,64 @@ impl DatabasePool {
         }
 
         if database_url.starts_with("mysql://") {
+            let mut connect_options = MySqlConnectOptions::from_str(database_url)?;
+            if let Some(charset) = &options.charset {
+                connect_options = connect_options.charset(charset);
+            }
+            if let Some(collation) = &options.collation {
+                connect_options = connect_options.collation(collation);
+            }
+            if let Some((cert, key)) = read_client_cert(options).await? {
+                connect_options = connect_options
+                    .ssl_client_cert_from_pem(cert)
+                    .ssl_client_key_from_pem(key);
+            }
+            let pool_options = with_pool_hooks(PoolOptions::<MySql>::new(), label, owner, options);
             let pool =
-                connect_with_timeout(timeout_duration, MySqlPool::connect(database_url)).await?;
+                connect_with_timeout(timeout_duration, pool_options.connect_with(connect_options))
+                    .await?;
             Ok(DatabasePool::MySql(pool))
         } else if database_url.starts_with("postgres://") {
+            let mut connect_options = PgConnectOptions::from_str(database_url)?;
+            if let Some((cert, key)) = read_client_cert(options).await? {
+                connect_options = connect_options
+                    .ssl_client_cert_from_pem(cert)
+                    .ssl_client_key_from_pem(key);
+            }
             let pool = connect_with_timeout(
                 timeout_duration,
-                PgPoolOptions::new()
-                    .max_connections(1)
-                    .acquire_timeout(Duration::from_secs(2))
-                    .connect(database_url),
+                with_pool_hooks(
+                    PgPoolOptions::new()
+                        .max_connections(1)
+                        .acquire_timeout(Duration::from_secs(2)),
+                    label,
+                    owner,
+                    options,
+                )
+                .connect_with(connect_options),
             )
             .await?;
             Ok(DatabasePool::Postgres(pool))
         } else if database_url.starts_with("sqlite://") {
+            if options.ssl_cert.is_some() {
+                return Err(sqlx::Error::Configuration(
+                    "ssl_cert/ssl_key are not supported for SQLite".into(),
+                ));
+            }
             if !Sqlite::database_exists(database_url).await? {
                 Sqlite::create_database(database_url).await?;
             }
+            let mut connect_options = SqliteConnectOptions::from_str(database_url)?;
+            if let Some(busy_timeout) = options.busy_timeout {
+                connect_options = connect_options.busy_timeout(Duration::from_millis(busy_timeout));
+            }
+            if options.wal {
+                connect_options = connect_options.journal_mode(SqliteJournalMode::Wal);
+            }
+            let pool_options = with_pool_hooks(PoolOptions::<Sqlite>::new(), label, owner, options);
             let pool =
-                connect_with_timeout(timeout_duration, SqlitePool::connect(database_url)).await?;
+                connect_with_timeout(timeout_duration, pool_options.connect_with(connect_options))
+                    .await?;
             Ok(DatabasePool::Sqlite(pool))
         } else {
             Err(sqlx::Error::Configuration(
@@ -79,10 +658,10 @@ impl DatabasePool {
         }
     }
 
-    fn make_query<'a, DB: sqlx::Database>(
+    fn make_query<'a, DB: BindExtra>(
         sql: &'a str,
         binds: &'a [QueryParams],
-    ) -> Result<sqlx::query::Query<'a, DB, <DB as sqlx::Database>::Arguments<'a>>, sqlx::Error>
+    ) -> Result<Query<'a, DB, <DB as sqlx::Database>::Arguments<'a>>, sqlx::Error>
     where
         bool: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
         i64: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
@@ -100,426 +679,5237 @@ impl DatabasePool {
                 QueryParams::Text(value) => query.bind(value.as_str()),
                 QueryParams::Json(value) => query.bind(value),
                 QueryParams::Bytes(value) => query.bind(value),
+                _ => DB::bind_extra(query, bind)?,
             };
         }
         Ok(query)
     }
 
-    async fn query(&self, request: &DatabaseQuery) -> Result<DatabaseResponse, sqlx::Error> {
+    async fn query(
+        &self,
+        request: &DatabaseQuery,
+        decode: &Arc<DecodeOptions>,
+        waiters: &AtomicI64,
+    ) -> Result<DatabaseResponse, sqlx::Error> {
         match self {
             DatabasePool::MySql(pool) => {
                 let query = Self::make_query(&request.sql, &request.binds)?;
-                let rows = query.fetch_all(pool).await?;
-                Ok(DatabaseResponse::MysqlRows(rows))
+                let mut conn = acquire(pool, waiters).await?;
+                let start = Instant::now();
+                if request.options.result_sets {
+                    let sets = fetch_result_sets(&mut *conn, query, is_call(&request.sql)).await?;
+                    let elapsed = start.elapsed();
+                    return Ok(DatabaseResponse::MysqlResultSets(
+                        sets.into_iter()
+                            .map(|rows| QueryRows {
+                                rows: Arc::new(rows),
+                                decode: decode.clone(),
+                                options: request.options.clone(),
+                                elapsed,
+                                affected: None,
+                                truncated: false,
+                            })
+                            .collect(),
+                    ));
+                }
+                let (mut rows, affected) = if request.options.with_count {
+                    let (rows, done) = fetch_with_count(&mut *conn, query).await?;
+                    (rows, Some(done.rows_affected()))
+                } else {
+                    let rows = fetch_rows(&mut *conn, query, request.options.max_rows).await?;
+                    (rows, None)
+                };
+                let truncated = truncate_rows(&mut rows, request.options.max_rows);
+                Ok(DatabaseResponse::MysqlRows(QueryRows {
+                    rows: Arc::new(rows),
+                    decode: decode.clone(),
+                    options: request.options.clone(),
+                    elapsed: start.elapsed(),
+                    affected,
+                    truncated,
+                }))
             }
             DatabasePool::Postgres(pool) => {
                 let query = Self::make_query(&request.sql, &request.binds)?;
-                let rows = query.fetch_all(pool).await?;
-                Ok(DatabaseResponse::PgRows(rows))
+                let mut conn = acquire(pool, waiters).await?;
+                let start = Instant::now();
+                let (mut rows, affected) = if request.options.with_count {
+                    let (rows, done) = fetch_with_count(&mut *conn, query).await?;
+                    (rows, Some(done.rows_affected()))
+                } else {
+                    let rows = fetch_rows(&mut *conn, query, request.options.max_rows).await?;
+                    (rows, None)
+                };
+                let truncated = truncate_rows(&mut rows, request.options.max_rows);
+                let elapsed = start.elapsed();
+                resolve_relations(&mut *conn, &rows, decode).await?;
+                Ok(DatabaseResponse::PgRows(QueryRows {
+                    rows: Arc::new(rows),
+                    decode: decode.clone(),
+                    options: request.options.clone(),
+                    elapsed,
+                    affected,
+                    truncated,
+                }))
             }
             DatabasePool::Sqlite(pool) => {
                 let query = Self::make_query(&request.sql, &request.binds)?;
-                let rows = query.fetch_all(pool).await?;
-                Ok(DatabaseResponse::SqliteRows(rows))
+                let mut conn = acquire(pool, waiters).await?;
+                let start = Instant::now();
+                let (mut rows, affected) = if request.options.with_count {
+                    let (rows, done) = fetch_with_count(&mut *conn, query).await?;
+                    (rows, Some(done.rows_affected()))
+                } else {
+                    let rows = fetch_rows(&mut *conn, query, request.options.max_rows).await?;
+                    (rows, None)
+                };
+                let truncated = truncate_rows(&mut rows, request.options.max_rows);
+                Ok(DatabaseResponse::SqliteRows(QueryRows {
+                    rows: Arc::new(rows),
+                    decode: decode.clone(),
+                    options: request.options.clone(),
+                    elapsed: start.elapsed(),
+                    affected,
+                    truncated,
+                }))
+            }
+        }
+    }
+
+    async fn query_in_transaction(
+        &self,
+        request: &DatabaseQuery,
+        decode: &Arc<DecodeOptions>,
+        waiters: &AtomicI64,
+    ) -> Result<DatabaseResponse, sqlx::Error> {
+        let mut transaction = self.begin(waiters).await?;
+        // an error drops the transaction, which rolls it back
+        let response = transaction.query(request, decode).await?;
+        transaction.finish(true).await?;
+        Ok(response)
+    }
+
+    async fn server_version(&self) -> Result<String, sqlx::Error> {
+        match self {
+            DatabasePool::MySql(pool) => {
+                sqlx::query_scalar("SELECT VERSION()").fetch_one(pool).await
             }
+            DatabasePool::Postgres(pool) => {
+                sqlx::query_scalar("SHOW server_version")
+                    .fetch_one(pool)
+                    .await
+            }
+            DatabasePool::Sqlite(pool) => {
+                sqlx::query_scalar("SELECT sqlite_version()")
+                    .fetch_one(pool)
+                    .await
+            }
+        }
+    }
+
+    async fn validate(&self, sql: &str) -> Result<(), sqlx::Error> {
+        match self {
+            DatabasePool::MySql(pool) => sqlx::query(sql).execute(pool).await.map(|_| ()),
+            DatabasePool::Postgres(pool) => sqlx::query(sql).execute(pool).await.map(|_| ()),
+            DatabasePool::Sqlite(pool) => sqlx::query(sql).execute(pool).await.map(|_| ()),
         }
     }
 
     async fn transaction(
         &self,
         requests: &[DatabaseQuery],
+        decode: &Arc<DecodeOptions>,
+        waiters: &AtomicI64,
+        slow: Option<&SlowTransaction<'_>>,
     ) -> Result<DatabaseResponse, sqlx::Error> {
         match self {
             DatabasePool::MySql(pool) => {
-                let mut transaction = pool.begin().await?;
-                for request in requests {
-                    let query = Self::make_query(&request.sql, &request.binds)?;
-                    query.execute(&mut *transaction).await?;
+                let mut conn = acquire(pool, waiters).await?;
+                let begun = Instant::now();
+                let res = async {
+                    let mut transaction = conn.begin().await?;
+                    let mut affected = Vec::with_capacity(requests.len());
+                    let mut results = Vec::new();
+                    for request in requests {
+                        let query = Self::make_query(&request.sql, &request.binds)?;
+                        let Some(label) = &request.options.label else {
+                            affected.push(query.execute(&mut *transaction).await?.rows_affected());
+                            continue;
+                        };
+                        let start = Instant::now();
+                        let (rows, done) = fetch_with_count(&mut *transaction, query).await?;
+                        affected.push(done.rows_affected());
+                        results.push((
+                            label.clone(),
+                            DatabaseResponse::MysqlRows(QueryRows {
+                                rows: Arc::new(rows),
+                                decode: decode.clone(),
+                                options: request.options.clone(),
+                                elapsed: start.elapsed(),
+                                affected: None,
+                                truncated: false,
+                            }),
+                        ));
+                    }
+                    transaction.commit().await?;
+                    Ok::<_, sqlx::Error>(DatabaseResponse::Transaction(affected, results))
+                }
+                .await;
+                // a rolled back transaction held its locks just as long
+                if let Some(slow) = slow {
+                    slow.check(begun, requests.len(), res.is_ok());
                 }
-                transaction.commit().await?;
-                Ok(DatabaseResponse::Transaction)
+                res
             }
             DatabasePool::Postgres(pool) => {
-                let mut transaction = pool.begin().await?;
-                for request in requests {
-                    let query = Self::make_query(&request.sql, &request.binds)?;
-                    query.execute(&mut *transaction).await?;
+                let mut conn = acquire(pool, waiters).await?;
+                let begun = Instant::now();
+                let res = async {
+                    let mut transaction = conn.begin().await?;
+                    let mut affected = Vec::with_capacity(requests.len());
+                    let mut results = Vec::new();
+                    for request in requests {
+                        let query = Self::make_query(&request.sql, &request.binds)?;
+                        let Some(label) = &request.options.label else {
+                            affected.push(query.execute(&mut *transaction).await?.rows_affected());
+                            continue;
+                        };
+                        let start = Instant::now();
+                        let (rows, done) = fetch_with_count(&mut *transaction, query).await?;
+                        affected.push(done.rows_affected());
+                        let elapsed = start.elapsed();
+                        resolve_relations(&mut *transaction, &rows, decode).await?;
+                        results.push((
+                            label.clone(),
+                            DatabaseResponse::PgRows(QueryRows {
+                                rows: Arc::new(rows),
+                                decode: decode.clone(),
+                                options: request.options.clone(),
+                                elapsed,
+                                affected: None,
+                                truncated: false,
+                            }),
+                        ));
+                    }
+                    transaction.commit().await?;
+                    Ok::<_, sqlx::Error>(DatabaseResponse::Transaction(affected, results))
+                }
+                .await;
+                // a rolled back transaction held its locks just as long
+                if let Some(slow) = slow {
+                    slow.check(begun, requests.len(), res.is_ok());
                 }
-                transaction.commit().await?;
-                Ok(DatabaseResponse::Transaction)
+                res
             }
             DatabasePool::Sqlite(pool) => {
-                let mut transaction = pool.begin().await?;
-                for request in requests {
-                    let query = Self::make_query(&request.sql, &request.binds)?;
-                    query.execute(&mut *transaction).await?;
+                let mut conn = acquire(pool, waiters).await?;
+                let begun = Instant::now();
+                let res = async {
+                    let mut transaction = conn.begin().await?;
+                    let mut affected = Vec::with_capacity(requests.len());
+                    let mut results = Vec::new();
+                    for request in requests {
+                        let query = Self::make_query(&request.sql, &request.binds)?;
+                        let Some(label) = &request.options.label else {
+                            affected.push(query.execute(&mut *transaction).await?.rows_affected());
+                            continue;
+                        };
+                        let start = Instant::now();
+                        let (rows, done) = fetch_with_count(&mut *transaction, query).await?;
+                        affected.push(done.rows_affected());
+                        results.push((
+                            label.clone(),
+                            DatabaseResponse::SqliteRows(QueryRows {
+                                rows: Arc::new(rows),
+                                decode: decode.clone(),
+                                options: request.options.clone(),
+                                elapsed: start.elapsed(),
+                                affected: None,
+                                truncated: false,
+                            }),
+                        ));
+                    }
+                    transaction.commit().await?;
+                    Ok::<_, sqlx::Error>(DatabaseResponse::Transaction(affected, results))
+                }
+                .await;
+                // a rolled back transaction held its locks just as long
+                if let Some(slow) = slow {
+                    slow.check(begun, requests.len(), res.is_ok());
                 }
-                transaction.commit().await?;
-                Ok(DatabaseResponse::Transaction)
+                res
             }
         }
     }
-}
 
-enum DatabaseRequest {
-    Query(u32, i64, DatabaseQuery), //owner, session, QueryBuilder
-    Transaction(u32, i64, Vec<DatabaseQuery>), //owner, session, Vec<QueryBuilder>
-    Close(),
-}
+    async fn close(&self) {
+        match self {
+            DatabasePool::MySql(pool) => pool.close().await,
+            DatabasePool::Postgres(pool) => pool.close().await,
+            DatabasePool::Sqlite(pool) => pool.close().await,
+        }
+    }
 
-#[derive(Clone)]
-struct DatabaseConnection {
-    tx: mpsc::Sender<DatabaseRequest>,
-    counter: Arc<AtomicI64>,
-}
+    async fn export(
+        &self,
+        request: &ExportRequest,
+        decode: &DecodeOptions,
+        waiters: &AtomicI64,
+    ) -> Result<DatabaseResponse, sqlx::Error> {
+        let query = &request.query;
+        let mut writer = ExportWriter::create(request).await?;
+        match self {
+            DatabasePool::MySql(pool) => {
+                let sql = Self::make_query(&query.sql, &query.binds)?;
+                let mut conn = acquire(pool, waiters).await?;
+                let mut rows = sql.fetch(&mut *conn);
+                while let Some(row) = rows.try_next().await? {
+                    writer
+                        .write_row::<MySql>(&row, &query.options, decode)
+                        .await?;
+                }
+            }
+            DatabasePool::Postgres(pool) => {
+                // a cursor read in chunks leaves the connection free to look regclass names up
+                // between them, the pool has no other connection to ask
+                let mut conn = acquire(pool, waiters).await?;
+                let mut tx = conn.begin().await?;
+                let declare = format!("DECLARE sqlx_export NO SCROLL CURSOR FOR {}", query.sql);
+                Self::make_query(&declare, &query.binds)?
+                    .persistent(false)
+                    .execute(&mut *tx)
+                    .await?;
+                let fetch = format!("FETCH {} FROM sqlx_export", EXPORT_CHUNK_ROWS);
+                loop {
+                    // the columns differ from one export to the next, nothing worth caching
+                    let rows = sqlx::query(&fetch)
+                        .persistent(false)
+                        .fetch_all(&mut *tx)
+                        .await?;
+                    if rows.is_empty() {
+                        break;
+                    }
+                    resolve_relations(&mut *tx, &rows, decode).await?;
+                    for row in &rows {
+                        writer
+                            .write_row::<Postgres>(row, &query.options, decode)
+                            .await?;
+                    }
+                }
+                tx.commit().await?;
+            }
+            DatabasePool::Sqlite(pool) => {
+                let sql = Self::make_query(&query.sql, &query.binds)?;
+                let mut conn = acquire(pool, waiters).await?;
+                let mut rows = sql.fetch(&mut *conn);
+                while let Some(row) = rows.try_next().await? {
+                    writer
+                        .write_row::<Sqlite>(&row, &query.options, decode)
+                        .await?;
+                }
+            }
+        }
+        Ok(DatabaseResponse::Exported(writer.finish().await?))
+    }
 
-enum DatabaseResponse {
-    Connect,
-    PgRows(Vec<PgRow>),
-    MysqlRows(Vec<MySqlRow>),
-    SqliteRows(Vec<SqliteRow>),
-    Error(sqlx::Error),
-    Timeout(String),
-    Transaction,
+    async fn describe(&self, sql: &str) -> Result<DatabaseResponse, sqlx::Error> {
+        fn columns<DB: Database>(describe: sqlx::Describe<DB>) -> DatabaseResponse {
+            let columns = describe
+                .columns()
+                .iter()
+                .enumerate()
+                .map(|(index, column)| {
+                    let type_name = column.type_info().name();
+                    ColumnDescription {
+                        name: column.name().to_string(),
+                        type_name: type_name.to_string(),
+                        db_type: DbType::from_name(type_name),
+                        nullable: describe.nullable(index),
+                    }
+                })
+                .collect();
+            DatabaseResponse::Describe(columns)
+        }
+
+        Ok(match self {
+            DatabasePool::MySql(pool) => columns(sqlx::Executor::describe(pool, sql).await?),
+            DatabasePool::Postgres(pool) => columns(sqlx::Executor::describe(pool, sql).await?),
+            DatabasePool::Sqlite(pool) => columns(sqlx::Executor::describe(pool, sql).await?),
+        })
+    }
+
+    /// SQLite without ANALYZE falls back to the largest rowid, or a count for WITHOUT ROWID tables
+    async fn estimate_count(&self, table: &str) -> Result<DatabaseResponse, sqlx::Error> {
+        let estimate: Option<i64> = match self {
+            // reltuples is -1 until the table is first vacuumed or analyzed
+            DatabasePool::Postgres(pool) => {
+                sqlx::query_scalar(
+                    "SELECT GREATEST(reltuples, 0)::int8 FROM pg_class WHERE oid = to_regclass($1)",
+                )
+                .bind(table)
+                .fetch_optional(pool)
+                .await?
+            }
+            DatabasePool::MySql(pool) => {
+                let (schema, name) = match table.split_once('.') {
+                    Some((schema, name)) => (Some(schema), name),
+                    None => (None, table),
+                };
+                sqlx::query_scalar::<_, Option<u64>>(
+                    "SELECT TABLE_ROWS FROM information_schema.tables \
+                     WHERE table_schema = COALESCE(?, DATABASE()) AND table_name = ?",
+                )
+                .bind(schema)
+                .bind(name)
+                .fetch_optional(pool)
+                .await?
+                .map(|rows| rows.unwrap_or(0) as i64)
+            }
+            DatabasePool::Sqlite(pool) => {
+                let (schema, name) = table.split_once('.').unwrap_or(("main", table));
+                // quoted, a table may be named after a keyword like `order`
+                let schema_name = quote_identifier(schema, '"');
+                let quoted = format!("{}.{}", schema_name, quote_identifier(name, '"'));
+                // the first number of a stat row is the table's row count, names match as
+                // case-insensitively as SQLite resolves them
+                let sql = format!(
+                    "SELECT stat FROM {}.sqlite_stat1 WHERE tbl = ? COLLATE NOCASE ORDER BY idx IS NOT NULL LIMIT 1",
+                    schema_name
+                );
+                let stat: Option<String> = match sqlx::query_scalar(&sql)
+                    .bind(name)
+                    .fetch_optional(pool)
+                    .await
+                {
+                    Ok(stat) => stat,
+                    // ANALYZE never ran on this schema
+                    Err(sqlx::Error::Database(err))
+                        if err.message().starts_with("no such table") =>
+                    {
+                        None
+                    }
+                    Err(err) => return Err(err),
+                };
+                match stat.and_then(|stat| stat.split(' ').next()?.parse().ok()) {
+                    Some(rows) => Some(rows),
+                    None => {
+                        let without_rowid: Option<bool> = sqlx::query_scalar(
+                            "SELECT wr FROM pragma_table_list \
+                             WHERE schema = ? COLLATE NOCASE AND name = ? COLLATE NOCASE",
+                        )
+                        .bind(schema)
+                        .bind(name)
+                        .fetch_optional(pool)
+                        .await?;
+                        match without_rowid {
+                            Some(without_rowid) => {
+                                // a WITHOUT ROWID table has no rowid to go by, it is counted instead
+                                let sql = if without_rowid {
+                                    format!("SELECT COUNT(*) FROM {}", quoted)
+                                } else {
+                                    format!("SELECT MAX(_rowid_) FROM {}", quoted)
+                                };
+                                sqlx::query_scalar::<_, Option<i64>>(&sql)
+                                    .fetch_one(pool)
+                                    .await
+                                    .map(|rows| Some(rows.unwrap_or(0)))?
+                            }
+                            None => None,
+                        }
+                    }
+                }
+            }
+        };
+        match estimate {
+            Some(rows) => Ok(DatabaseResponse::Estimated(rows)),
+            None => Err(sqlx::Error::InvalidArgument(format!(
+                "table '{}' not found",
+                table
+            ))),
+        }
+    }
+
+    /// Closes idle connections only, ones in use and pinned sessions are left alone
+    async fn recycle(&self) -> u64 {
+        async fn close_idle<DB: Database>(pool: &sqlx::Pool<DB>) -> u64 {
+            let mut closed = 0;
+            for _ in 0..pool.num_idle() {
+                let Some(conn) = pool.try_acquire() else {
+                    break;
+                };
+                // the connection is dropped from the pool either way
+                let _ = conn.close().await;
+                closed += 1;
+            }
+            closed
+        }
+
+        match self {
+            DatabasePool::MySql(pool) => close_idle(pool).await,
+            DatabasePool::Postgres(pool) => close_idle(pool).await,
+            DatabasePool::Sqlite(pool) => close_idle(pool).await,
+        }
+    }
+
+    async fn begin(&self, waiters: &AtomicI64) -> Result<PinnedTransaction, sqlx::Error> {
+        let _waiter = AcquireWaiter::new(waiters);
+        Ok(match self {
+            DatabasePool::MySql(pool) => PinnedTransaction::MySql(pool.begin().await?),
+            DatabasePool::Postgres(pool) => PinnedTransaction::Postgres(pool.begin().await?),
+            DatabasePool::Sqlite(pool) => PinnedTransaction::Sqlite(pool.begin().await?),
+        })
+    }
 }
 
-#[derive(Debug, Clone)]
-enum QueryParams {
-    Bool(bool),
-    Int(i64),
-    Float(f64),
-    Text(String),
-    Json(serde_json::Value),
-    Bytes(Vec<u8>),
+/// Dropping it rolls back and returns the connection to the pool
+enum PinnedTransaction {
+    MySql(sqlx::Transaction<'static, MySql>),
+    Postgres(sqlx::Transaction<'static, Postgres>),
+    Sqlite(sqlx::Transaction<'static, Sqlite>),
 }
 
-#[derive(Debug, Clone)]
-struct DatabaseQuery {
-    sql: String,
-    binds: Vec<QueryParams>,
-}
-
-async fn handle_result(
-    database_url: &str,
-    failed_times: &mut i32,
-    counter: &Arc<AtomicI64>,
-    protocol_type: u8,
-    owner: u32,
-    session: i64,
-    res: Result<DatabaseResponse, sqlx::Error>,
-) -> bool {
-    match res {
-        Ok(rows) => {
-            moon_send(protocol_type, owner, session, rows);
-            if *failed_times > 0 {
-                moon_log(
-                    owner,
-                    LOG_LEVEL_INFO,
-                    format!(
-                        "Database '{}' recover from error. Retry success.",
-                        database_url
-                    ),
-                );
+impl PinnedTransaction {
+    async fn query(
+        &mut self,
+        request: &DatabaseQuery,
+        decode: &Arc<DecodeOptions>,
+    ) -> Result<DatabaseResponse, sqlx::Error> {
+        match self {
+            PinnedTransaction::MySql(transaction) => {
+                let query = DatabasePool::make_query(&request.sql, &request.binds)?;
+                let start = Instant::now();
+                if request.options.result_sets {
+                    let sets =
+                        fetch_result_sets(&mut **transaction, query, is_call(&request.sql)).await?;
+                    let elapsed = start.elapsed();
+                    return Ok(DatabaseResponse::MysqlResultSets(
+                        sets.into_iter()
+                            .map(|rows| QueryRows {
+                                rows: Arc::new(rows),
+                                decode: decode.clone(),
+                                options: request.options.clone(),
+                                elapsed,
+                                affected: None,
+                                truncated: false,
+                            })
+                            .collect(),
+                    ));
+                }
+                let (mut rows, affected) = if request.options.with_count {
+                    let (rows, done) = fetch_with_count(&mut **transaction, query).await?;
+                    (rows, Some(done.rows_affected()))
+                } else {
+                    let rows =
+                        fetch_rows(&mut **transaction, query, request.options.max_rows).await?;
+                    (rows, None)
+                };
+                let truncated = truncate_rows(&mut rows, request.options.max_rows);
+                Ok(DatabaseResponse::MysqlRows(QueryRows {
+                    rows: Arc::new(rows),
+                    decode: decode.clone(),
+                    options: request.options.clone(),
+                    elapsed: start.elapsed(),
+                    affected,
+                    truncated,
+                }))
+            }
+            PinnedTransaction::Postgres(transaction) => {
+                let query = DatabasePool::make_query(&request.sql, &request.binds)?;
+                let start = Instant::now();
+                let (mut rows, affected) = if request.options.with_count {
+                    let (rows, done) = fetch_with_count(&mut **transaction, query).await?;
+                    (rows, Some(done.rows_affected()))
+                } else {
+                    let rows =
+                        fetch_rows(&mut **transaction, query, request.options.max_rows).await?;
+                    (rows, None)
+                };
+                let truncated = truncate_rows(&mut rows, request.options.max_rows);
+                let elapsed = start.elapsed();
+                resolve_relations(&mut **transaction, &rows, decode).await?;
+                Ok(DatabaseResponse::PgRows(QueryRows {
+                    rows: Arc::new(rows),
+                    decode: decode.clone(),
+                    options: request.options.clone(),
+                    elapsed,
+                    affected,
+                    truncated,
+                }))
+            }
+            PinnedTransaction::Sqlite(transaction) => {
+                let query = DatabasePool::make_query(&request.sql, &request.binds)?;
+                let start = Instant::now();
+                let (mut rows, affected) = if request.options.with_count {
+                    let (rows, done) = fetch_with_count(&mut **transaction, query).await?;
+                    (rows, Some(done.rows_affected()))
+                } else {
+                    let rows =
+                        fetch_rows(&mut **transaction, query, request.options.max_rows).await?;
+                    (rows, None)
+                };
+                let truncated = truncate_rows(&mut rows, request.options.max_rows);
+                Ok(DatabaseResponse::SqliteRows(QueryRows {
+                    rows: Arc::new(rows),
+                    decode: decode.clone(),
+                    options: request.options.clone(),
+                    elapsed: start.elapsed(),
+                    affected,
+                    truncated,
+                }))
             }
-            counter.fetch_sub(1, std::sync::atomic::Ordering::Release);
-            false
         }
-        Err(err) => {
-            if session != 0 {
-                moon_send(protocol_type, owner, session, DatabaseResponse::Error(err));
-                counter.fetch_sub(1, std::sync::atomic::Ordering::Release);
-                false
-            } else {
-                if *failed_times > 0 {
-                    moon_log(
-                        owner,
-                        LOG_LEVEL_ERROR,
-                        format!(
-                            "Database '{}' error: '{:?}'. Will retry.",
-                            database_url,
-                            err.to_string()
-                        ),
-                    );
+    }
+
+    async fn execute_batch(&mut self, querys: &[DatabaseQuery]) -> Result<Vec<u64>, sqlx::Error> {
+        let mut affected = Vec::with_capacity(querys.len());
+        for request in querys {
+            let done = match self {
+                PinnedTransaction::MySql(transaction) => {
+                    DatabasePool::make_query(&request.sql, &request.binds)?
+                        .execute(&mut **transaction)
+                        .await?
+                        .rows_affected()
                 }
-                *failed_times += 1;
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                true
-            }
+                PinnedTransaction::Postgres(transaction) => {
+                    DatabasePool::make_query(&request.sql, &request.binds)?
+                        .execute(&mut **transaction)
+                        .await?
+                        .rows_affected()
+                }
+                PinnedTransaction::Sqlite(transaction) => {
+                    DatabasePool::make_query(&request.sql, &request.binds)?
+                        .execute(&mut **transaction)
+                        .await?
+                        .rows_affected()
+                }
+            };
+            affected.push(done);
         }
+        Ok(affected)
     }
-}
 
-async fn database_handler(
-    protocol_type: u8,
-    pool: &DatabasePool,
-    mut rx: mpsc::Receiver<DatabaseRequest>,
-    database_url: &str,
-    counter: Arc<AtomicI64>,
-) {
-    while let Some(op) = rx.recv().await {
-        let mut failed_times = 0;
-        match &op {
-            DatabaseRequest::Query(owner, session, query_op) => {
-                while handle_result(
-                    database_url,
-                    &mut failed_times,
-                    &counter,
-                    protocol_type,
-                    *owner,
-                    *session,
-                    pool.query(query_op).await,
-                )
-                .await
-                {}
-            }
-            DatabaseRequest::Transaction(owner, session, query_ops) => {
-                while handle_result(
-                    database_url,
-                    &mut failed_times,
-                    &counter,
-                    protocol_type,
-                    *owner,
-                    *session,
-                    pool.transaction(query_ops).await,
-                )
-                .await
-                {}
-            }
-            DatabaseRequest::Close() => {
-                break;
-            }
+    async fn finish(self, commit: bool) -> Result<(), sqlx::Error> {
+        match (self, commit) {
+            (PinnedTransaction::MySql(transaction), true) => transaction.commit().await,
+            (PinnedTransaction::MySql(transaction), false) => transaction.rollback().await,
+            (PinnedTransaction::Postgres(transaction), true) => transaction.commit().await,
+            (PinnedTransaction::Postgres(transaction), false) => transaction.rollback().await,
+            (PinnedTransaction::Sqlite(transaction), true) => transaction.commit().await,
+            (PinnedTransaction::Sqlite(transaction), false) => transaction.rollback().await,
         }
     }
 }
 
-extern "C-unwind" fn connect(state: LuaState) -> i32 {
-    let protocol_type: u8 = laux::lua_get(state, 1);
-    let owner = laux::lua_get(state, 2);
-    let session: i64 = laux::lua_get(state, 3);
+enum SessionRequest {
+    Query(u32, i64, DatabaseQuery),      //owner, session, QueryBuilder
+    Batch(u32, i64, Vec<DatabaseQuery>), //owner, session, one execute_batch chunk
+    Finish(u32, i64, bool),              //owner, session, commit
+}
 
-    let database_url: &str = laux::lua_get(state, 4);
-    let name: &str = laux::lua_get(state, 5);
-    let connect_timeout: u64 = laux::lua_opt(state, 6).unwrap_or(5000);
+struct TransactionSession {
+    tx: mpsc::Sender<SessionRequest>,
+    raise_errors: bool,
+    no_json_autodetect: bool,
+    backslash_escapes: bool,
+}
 
-    CONTEXT.tokio_runtime.spawn(async move {
-        match DatabasePool::connect(database_url, Duration::from_millis(connect_timeout)).await {
-            Ok(pool) => {
-                let (tx, rx) = mpsc::channel(100);
-                let counter = Arc::new(AtomicI64::new(0));
-                DATABASE_CONNECTIONSS.insert(
-                    name.to_string(),
-                    DatabaseConnection {
-                        tx: tx.clone(),
-                        counter: counter.clone(),
-                    },
-                );
-                moon_send(protocol_type, owner, session, DatabaseResponse::Connect);
-                database_handler(protocol_type, &pool, rx, database_url, counter).await;
+/// Ends after commit/rollback, or when every handle is dropped, which rolls back
+async fn session_handler(
+    protocol_type: u8,
+    mut transaction: PinnedTransaction,
+    mut rx: mpsc::Receiver<SessionRequest>,
+    decode: Arc<DecodeOptions>,
+    stats: Arc<ConnectionStats>,
+    label: String,
+    records: (DatabasePool, CompositeTypes),
+) {
+    let (pool, composite_types) = records;
+    let mut shutdown = db_shutdown_receiver();
+    loop {
+        let op = tokio::select! {
+            op = rx.recv() => match op {
+                Some(op) => Some(op),
+                None => return,
+            },
+            _ = shutdown.wait_for(|stop| *stop) => None,
+        };
+        // the pinned connection would keep pool.close() waiting on Lua to commit or roll back
+        let Some(op) = op else {
+            rx.close();
+            while let Ok(op) = rx.try_recv() {
+                let (SessionRequest::Query(owner, session, _)
+                | SessionRequest::Batch(owner, session, _)
+                | SessionRequest::Finish(owner, session, _)) = op;
+                let err = sqlx::Error::Protocol("transaction rolled back by shutdown".into());
+                stats.send(protocol_type, owner, session, DatabaseResponse::Error(err));
             }
-            Err(err) => {
-                moon_send(
-                    protocol_type,
-                    owner,
-                    session,
-                    DatabaseResponse::Timeout(err.to_string()),
+            if let Err(err) = transaction.finish(false).await {
+                moon_log(
+                    0,
+                    LOG_LEVEL_ERROR,
+                    format!("Database '{}' shutdown rollback failed: {}", label, err),
                 );
             }
+            return;
         };
-    });
-
-    laux::lua_push(state, session);
-    1
-}
-
-fn get_query_param(state: LuaState, i: i32) -> Result<QueryParams, String> {
-    let options = JsonOptions::default();
-
-    let res = match LuaValue::from_stack(state, i) {
-        LuaValue::Boolean(val) => QueryParams::Bool(val),
-        LuaValue::Number(val) => QueryParams::Float(val),
-        LuaValue::Integer(val) => QueryParams::Int(val),
-        LuaValue::String(val) => {
-            if val.starts_with(b"{") || val.starts_with(b"[") {
-                if let Ok(value) = serde_json::from_slice::<serde_json::Value>(val) {
-                    QueryParams::Json(value)
-                } else {
-                    QueryParams::Text(unsafe { String::from_utf8_unchecked(val.to_vec()) })
+        if stats.trace.load(std::sync::atomic::Ordering::Relaxed) {
+            match &op {
+                SessionRequest::Query(owner, _, query) => trace_query(*owner, &label, query),
+                SessionRequest::Batch(owner, _, querys) => {
+                    for query in querys {
+                        trace_query(*owner, &label, query);
+                    }
                 }
-            } else {
-                QueryParams::Text(unsafe { String::from_utf8_unchecked(val.to_vec()) })
+                SessionRequest::Finish(..) => {}
             }
         }
-        LuaValue::Table(val) => {
-            let mut buffer = Vec::new();
-            if let Err(err) = encode_table(&mut buffer, &val, 0, false, &options) {
-                drop(buffer);
-                laux::lua_error(state, err);
+        match op {
+            SessionRequest::Query(owner, session, query_op) => {
+                let res = match composite_types
+                    .check(&pool, std::slice::from_ref(&query_op))
+                    .await
+                {
+                    Ok(()) => transaction.query(&query_op, &decode).await,
+                    Err(err) => Err(err),
+                };
+                let response = res.unwrap_or_else(DatabaseResponse::Error);
+                stats.send(protocol_type, owner, session, response);
             }
-            if buffer[0] == b'{' || buffer[0] == b'[' {
-                if let Ok(value) = serde_json::from_slice::<serde_json::Value>(buffer.as_slice()) {
-                    QueryParams::Json(value)
-                } else {
-                    QueryParams::Bytes(buffer)
-                }
-            } else {
-                QueryParams::Bytes(buffer)
+            SessionRequest::Batch(owner, session, querys) => {
+                let res = match composite_types.check(&pool, &querys).await {
+                    Ok(()) => transaction.execute_batch(&querys).await,
+                    Err(err) => Err(err),
+                };
+                let response = match res {
+                    Ok(affected) => DatabaseResponse::Transaction(affected, Vec::new()),
+                    Err(err) => DatabaseResponse::Error(err),
+                };
+                stats.send(protocol_type, owner, session, response);
+            }
+            SessionRequest::Finish(owner, session, commit) => {
+                let response = match transaction.finish(commit).await {
+                    Ok(()) => DatabaseResponse::Transaction(Vec::new(), Vec::new()),
+                    Err(err) => DatabaseResponse::Error(err),
+                };
+                stats.send(protocol_type, owner, session, response);
+                return;
             }
         }
-        _t => {
-            return Err(format!(
-                "get_query_param: unsupport value type :{}",
-                laux::type_name(state, i)
-            ));
-        }
-    };
-    Ok(res)
+    }
 }
 
-extern "C-unwind" fn query(state: LuaState) -> i32 {
-    let mut args = LuaArgs::new(1);
-    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
-        .expect("Invalid database connect pointer");
+trait BindExtra: sqlx::Database {
+    fn bind_extra<'a>(
+        query: Query<'a, Self, <Self as sqlx::Database>::Arguments<'a>>,
+        param: &'a QueryParams,
+    ) -> Result<Query<'a, Self, <Self as sqlx::Database>::Arguments<'a>>, sqlx::Error>;
+}
 
-    let owner = laux::lua_get(state, args.iter_arg());
-    let session = laux::lua_get(state, args.iter_arg());
+impl BindExtra for Postgres {
+    fn bind_extra<'a>(
+        query: Query<'a, Self, <Self as sqlx::Database>::Arguments<'a>>,
+        param: &'a QueryParams,
+    ) -> Result<Query<'a, Self, <Self as sqlx::Database>::Arguments<'a>>, sqlx::Error> {
+        Ok(match param {
+            QueryParams::JsonText(value) => query.bind(PgJson(value.to_string())),
+            QueryParams::Jsonb(value) => query.bind(sqlx::types::Json(value)),
+            QueryParams::Interval(seconds) => query.bind(seconds_interval(*seconds)),
+            QueryParams::IntervalArray(values) => query.bind(
+                values
+                    .iter()
+                    .map(|seconds| seconds_interval(*seconds))
+                    .collect::<Vec<_>>(),
+            ),
+            QueryParams::Record { literal, .. } => query.bind(literal.as_str()),
+            QueryParams::BytesArray(values) => query.bind(values),
+            QueryParams::BoolArray(values) => query.bind(values),
+            QueryParams::Time(time) => query.bind(*time),
+            QueryParams::TsQuery(text) => query.bind(text.as_str()),
+            QueryParams::Decimal(value) => query.bind(*value),
+            QueryParams::Null => query.bind(PgNull),
+            _ => return Err(unsupported_bind()),
+        })
+    }
+}
 
-    let sql = laux::lua_get::<&str>(state, args.iter_arg());
-    let mut params = Vec::new();
-    let top = laux::lua_top(state);
-    for i in args.iter_arg()..=top {
-        let param = get_query_param(state, i);
-        match param {
-            Ok(value) => {
-                params.push(value);
+impl BindExtra for MySql {
+    fn bind_extra<'a>(
+        query: Query<'a, Self, <Self as sqlx::Database>::Arguments<'a>>,
+        param: &'a QueryParams,
+    ) -> Result<Query<'a, Self, <Self as sqlx::Database>::Arguments<'a>>, sqlx::Error> {
+        Ok(match param {
+            QueryParams::JsonText(value) | QueryParams::Jsonb(value) => query.bind(value),
+            QueryParams::Interval(seconds) => bind_seconds(query, *seconds),
+            QueryParams::Time(time) => query.bind(*time),
+            QueryParams::Decimal(value) => query.bind(*value),
+            QueryParams::Null => query.bind(None::<&str>),
+            QueryParams::Record { .. } => {
+                return Err(sqlx::Error::Encode(
+                    "record binds are only supported on PostgreSQL".into(),
+                ));
             }
-            Err(err) => {
-                push_lua_table!(
-                    state,
-                    "kind" => "ERROR",
-                    "message" => err
-                );
-                return 1;
+            QueryParams::BytesArray(_) => {
+                return Err(sqlx::Error::Encode(
+                    "bytea_array binds are only supported on PostgreSQL".into(),
+                ));
             }
-        }
-    }
-
-    match conn.tx.try_send(DatabaseRequest::Query(
-        owner,
-        session,
-        DatabaseQuery {
-            sql: sql.to_string(),
-            binds: params,
-        },
-    )) {
-        Ok(_) => {
-            conn.counter
-                .fetch_add(1, std::sync::atomic::Ordering::Release);
-            laux::lua_push(state, session);
-            1
-        }
-        Err(err) => {
-            push_lua_table!(
-                state,
-                "kind" => "ERROR",
-                "message" => err.to_string()
-            );
-            1
-        }
+            QueryParams::IntervalArray(_) => {
+                return Err(sqlx::Error::Encode(
+                    "interval_array binds are only supported on PostgreSQL".into(),
+                ));
+            }
+            QueryParams::BoolArray(_) => {
+                return Err(sqlx::Error::Encode(
+                    "bool_array binds are only supported on PostgreSQL".into(),
+                ));
+            }
+            QueryParams::TsQuery(_) => {
+                return Err(sqlx::Error::Encode(
+                    "tsquery binds are only supported on PostgreSQL".into(),
+                ));
+            }
+            _ => return Err(unsupported_bind()),
+        })
     }
 }
 
-struct TransactionQuerys {
-    querys: Vec<DatabaseQuery>,
-}
-
-extern "C-unwind" fn push_transaction_query(state: LuaState) -> i32 {
-    let querys = laux::lua_touserdata::<TransactionQuerys>(state, 1)
-        .expect("Invalid transaction query pointer");
-
-    let sql = laux::lua_get::<&str>(state, 2);
-    let mut params = Vec::new();
-    let top = laux::lua_top(state);
-    for i in 3..=top {
-        let param = get_query_param(state, i);
-        match param {
-            Ok(value) => {
-                params.push(value);
+impl BindExtra for Sqlite {
+    fn bind_extra<'a>(
+        query: Query<'a, Self, <Self as sqlx::Database>::Arguments<'a>>,
+        param: &'a QueryParams,
+    ) -> Result<Query<'a, Self, <Self as sqlx::Database>::Arguments<'a>>, sqlx::Error> {
+        Ok(match param {
+            QueryParams::JsonText(value) | QueryParams::Jsonb(value) => query.bind(value),
+            QueryParams::Interval(seconds) => bind_seconds(query, *seconds),
+            QueryParams::Time(time) => query.bind(*time),
+            // no decimal type, the text keeps every digit for NUMERIC/TEXT columns
+            QueryParams::Decimal(value) => query.bind(value.to_string()),
+            QueryParams::Null => query.bind(None::<&str>),
+            QueryParams::Record { .. } => {
+                return Err(sqlx::Error::Encode(
+                    "record binds are only supported on PostgreSQL".into(),
+                ));
             }
-            Err(err) => {
-                drop(params);
-                laux::lua_error(state, err);
+            QueryParams::BytesArray(_) => {
+                return Err(sqlx::Error::Encode(
+                    "bytea_array binds are only supported on PostgreSQL".into(),
+                ));
             }
-        }
+            QueryParams::IntervalArray(_) => {
+                return Err(sqlx::Error::Encode(
+                    "interval_array binds are only supported on PostgreSQL".into(),
+                ));
+            }
+            QueryParams::BoolArray(_) => {
+                return Err(sqlx::Error::Encode(
+                    "bool_array binds are only supported on PostgreSQL".into(),
+                ));
+            }
+            QueryParams::TsQuery(_) => {
+                return Err(sqlx::Error::Encode(
+                    "tsquery binds are only supported on PostgreSQL".into(),
+                ));
+            }
+            _ => return Err(unsupported_bind()),
+        })
     }
+}
 
-    querys.querys.push(DatabaseQuery {
-        sql: sql.to_string(),
-        binds: params,
-    });
+/// Skipping a bind would shift every later placeholder
+fn unsupported_bind() -> sqlx::Error {
+    sqlx::Error::Encode("parameter type has no bind for this database".into())
+}
 
-    0
+fn seconds_interval(seconds: f64) -> PgInterval {
+    PgInterval {
+        months: 0,
+        days: 0,
+        microseconds: (seconds * 1_000_000.0).round() as i64,
+    }
 }
 
-extern "C-unwind" fn make_transaction(state: LuaState) -> i32 {
-    laux::lua_newuserdata(
-        state,
-        TransactionQuerys { querys: Vec::new() },
-        cstr!("sqlx_transaction_metatable"),
-        &[lreg!("push", push_transaction_query), lreg_null!()],
-    );
-    1
+/// MySQL/SQLite have no interval type, durations are stored as seconds
+fn bind_seconds<'a, DB: Database>(
+    query: Query<'a, DB, <DB as Database>::Arguments<'a>>,
+    seconds: f64,
+) -> Query<'a, DB, <DB as Database>::Arguments<'a>>
+where
+    i64: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+    f64: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+{
+    if seconds.fract() == 0.0 {
+        query.bind(seconds as i64)
+    } else {
+        query.bind(seconds)
+    }
 }
 
-extern "C-unwind" fn transaction(state: LuaState) -> i32 {
-    let mut args = LuaArgs::new(1);
-    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
-        .expect("Invalid database connect pointer");
+trait DecodeExtra: Database {
+    fn overrides(_db_type: DbType) -> bool {
+        false
+    }
 
-    let owner = laux::lua_get(state, args.iter_arg());
-    let session = laux::lua_get(state, args.iter_arg());
+    fn db_type(type_info: &<Self as Database>::TypeInfo) -> DbType {
+        DbType::from_name(type_info.name())
+    }
 
-    let querys = laux::lua_touserdata::<TransactionQuerys>(state, args.iter_arg())
-        .expect("Invalid transaction query pointer");
+    fn insert_extra(
+        state: LuaState,
+        table: &LuaTable,
+        column_name: &str,
+        value: <Self as Database>::ValueRef<'_>,
+        db_type: DbType,
+        rows_options: &RowsOptions,
+    ) -> Result<(), String>;
 
-    match conn.tx.try_send(DatabaseRequest::Transaction(
-        owner,
-        session,
-        std::mem::take(&mut querys.querys),
-    )) {
-        Ok(_) => {
-            conn.counter
-                .fetch_add(1, std::sync::atomic::Ordering::Release);
-            laux::lua_push(state, session);
-            1
-        }
-        Err(err) => {
-            push_lua_table!(
-                state,
-                "kind" => "ERROR",
-                "message" => err.to_string()
-            );
-            1
-        }
-    }
+    fn extra_json(
+        value: <Self as Database>::ValueRef<'_>,
+        db_type: DbType,
+        rows_options: &RowsOptions,
+    ) -> serde_json::Value;
 }
 
-extern "C-unwind" fn close(state: LuaState) -> i32 {
-    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
-        .expect("Invalid database connect pointer");
+impl DecodeExtra for Postgres {
+    /// Composite array types are named after the user's type, e.g. `_item`
+    fn db_type(type_info: &PgTypeInfo) -> DbType {
+        match type_info.kind() {
+            PgTypeKind::Array(element) if matches!(element.kind(), PgTypeKind::Composite(_)) => {
+                DbType::CompositeArray
+            }
+            _ => DbType::from_name(type_info.name()),
+        }
+    }
+
+    fn insert_extra(
+        state: LuaState,
+        table: &LuaTable,
+        column_name: &str,
+        value: <Self as Database>::ValueRef<'_>,
+        db_type: DbType,
+        rows_options: &RowsOptions,
+    ) -> Result<(), String> {
+        match db_type {
+            DbType::Interval => match <PgInterval as sqlx::Decode<Postgres>>::decode(value) {
+                Ok(interval) => {
+                    table.insert(column_name, interval_seconds(&interval));
+                }
+                Err(_) => {
+                    table.insert(column_name, LuaNil {});
+                }
+            },
+            // binary MONEY is the raw amount in cents, independent of the server's lc_monetary
+            DbType::Money => match <PgMoney as sqlx::Decode<Postgres>>::decode(value) {
+                Ok(money) => {
+                    table.insert(column_name, format_money(money.0));
+                }
+                Err(_) => {
+                    table.insert(column_name, LuaNil {});
+                }
+            },
+            // PostGIS sends geometry/geography as EWKB over the binary protocol
+            DbType::Geometry => match <&[u8] as sqlx::Decode<Postgres>>::decode(value) {
+                Ok(wkb) => {
+                    insert_geometry(state, table, column_name, ewkb_srid(wkb), wkb, rows_options);
+                }
+                Err(_) => {
+                    table.insert(column_name, LuaNil {});
+                }
+            },
+            // NULL elements are left as holes by `push_pg_array`
+            DbType::BytesArray => {
+                match <Vec<Option<Vec<u8>>> as sqlx::Decode<Postgres>>::decode(value) {
+                    Ok(items) => {
+                        laux::lua_push(state, column_name);
+                        push_pg_array(state, &items, |bytes| {
+                            laux::lua_push(state, bytes.as_slice());
+                            Ok(())
+                        })?;
+                        table.insert_from_stack();
+                    }
+                    Err(_) => {
+                        table.insert(column_name, LuaNil {});
+                    }
+                }
+            }
+            DbType::IntervalArray => {
+                match <Vec<Option<PgInterval>> as sqlx::Decode<Postgres>>::decode(value) {
+                    Ok(items) => {
+                        laux::lua_push(state, column_name);
+                        push_pg_array(state, &items, |interval| {
+                            laux::lua_push(state, format_interval(interval));
+                            Ok(())
+                        })?;
+                        table.insert_from_stack();
+                    }
+                    Err(_) => {
+                        table.insert(column_name, LuaNil {});
+                    }
+                }
+            }
+            DbType::BoolArray => {
+                match <Vec<Option<bool>> as sqlx::Decode<Postgres>>::decode(value) {
+                    Ok(items) => {
+                        laux::lua_push(state, column_name);
+                        push_pg_array(state, &items, |flag| {
+                            laux::lua_push(state, *flag);
+                            Ok(())
+                        })?;
+                        table.insert_from_stack();
+                    }
+                    Err(_) => {
+                        table.insert(column_name, LuaNil {});
+                    }
+                }
+            }
+            // best effort, see `parse_composite_array`; raw bytes when the value doesn't parse.
+            // Each element is a positional field array, NULL fields are json.null to keep positions
+            DbType::CompositeArray => match <&[u8] as sqlx::Decode<Postgres>>::decode(value) {
+                Ok(bytes) => match parse_composite_array(bytes) {
+                    Some(items) => {
+                        ensure_row_stack(state, 0)?;
+                        laux::lua_push(state, column_name);
+                        push_pg_array(state, &items, |fields| {
+                            let record = LuaTable::new(state, fields.len(), 0);
+                            for (j, field) in fields.iter().enumerate() {
+                                field.push(state);
+                                record.rawseti(j + 1);
+                            }
+                            Ok(())
+                        })?;
+                        table.insert_from_stack();
+                    }
+                    None => {
+                        table.insert(column_name, bytes);
+                    }
+                },
+                Err(_) => {
+                    table.insert(column_name, LuaNil {});
+                }
+            },
+            // elements follow the query's json_as_table
+            DbType::JsonArray => {
+                match <Vec<Option<serde_json::Value>> as sqlx::Decode<Postgres>>::decode(value) {
+                    Ok(items) => {
+                        ensure_row_stack(state, 0)?;
+                        laux::lua_push(state, column_name);
+                        push_pg_array(state, &items, |json| {
+                            if rows_options.json_as_table {
+                                push_json(state, json, rows_options.json_numbers)
+                            } else {
+                                laux::lua_push(state, json.to_string());
+                                Ok(())
+                            }
+                        })?;
+                        table.insert_from_stack();
+                    }
+                    Err(_) => {
+                        table.insert(column_name, LuaNil {});
+                    }
+                }
+            }
+            // binary full-text values are rebuilt into the text Postgres prints for them
+            DbType::TsVector | DbType::TsQuery => {
+                match <&[u8] as sqlx::Decode<Postgres>>::decode(value) {
+                    Ok(bytes) => {
+                        let text = if matches!(db_type, DbType::TsVector) {
+                            tsvector_to_text(bytes)
+                        } else {
+                            tsquery_to_text(bytes)
+                        };
+                        match text {
+                            Some(text) => table.insert(column_name, text.as_str()),
+                            None => table.insert(column_name, bytes),
+                        };
+                    }
+                    Err(_) => {
+                        table.insert(column_name, LuaNil {});
+                    }
+                }
+            }
+            DbType::PgChar => match <i8 as sqlx::Decode<Postgres>>::decode(value) {
+                Ok(byte) => {
+                    table.insert(column_name, pg_char_text(byte as u8));
+                }
+                Err(_) => {
+                    table.insert(column_name, LuaNil {});
+                }
+            },
+            _ => {
+                table.insert(column_name, LuaNil {});
+            }
+        }
+        Ok(())
+    }
+
+    fn extra_json(
+        value: <Self as Database>::ValueRef<'_>,
+        db_type: DbType,
+        rows_options: &RowsOptions,
+    ) -> serde_json::Value {
+        use serde_json::Value;
+
+        match db_type {
+            DbType::Interval => <PgInterval as sqlx::Decode<Postgres>>::decode(value)
+                .map(|interval| Value::from(interval_seconds(&interval)))
+                .unwrap_or(Value::Null),
+            DbType::Money => <PgMoney as sqlx::Decode<Postgres>>::decode(value)
+                .map(|money| Value::from(format_money(money.0)))
+                .unwrap_or(Value::Null),
+            DbType::Geometry => <&[u8] as sqlx::Decode<Postgres>>::decode(value)
+                .map(|wkb| geometry_json(ewkb_srid(wkb), wkb, rows_options))
+                .unwrap_or(Value::Null),
+            DbType::BytesArray => <Vec<Option<Vec<u8>>> as sqlx::Decode<Postgres>>::decode(value)
+                .map(|items| {
+                    items
+                        .iter()
+                        .map(|item| {
+                            item.as_deref()
+                                .map_or(Value::Null, |b| bytes_text(b).into())
+                        })
+                        .collect()
+                })
+                .unwrap_or(Value::Null),
+            DbType::IntervalArray => {
+                <Vec<Option<PgInterval>> as sqlx::Decode<Postgres>>::decode(value)
+                    .map(|items| {
+                        items
+                            .iter()
+                            .map(|item| {
+                                item.as_ref()
+                                    .map_or(Value::Null, |i| format_interval(i).into())
+                            })
+                            .collect()
+                    })
+                    .unwrap_or(Value::Null)
+            }
+            DbType::BoolArray => <Vec<Option<bool>> as sqlx::Decode<Postgres>>::decode(value)
+                .map(|items| items.into_iter().map(Value::from).collect())
+                .unwrap_or(Value::Null),
+            DbType::CompositeArray => match <&[u8] as sqlx::Decode<Postgres>>::decode(value) {
+                Ok(bytes) => match parse_composite_array(bytes) {
+                    Some(items) => items
+                        .into_iter()
+                        .map(|item| {
+                            item.map_or(Value::Null, |fields| {
+                                fields.into_iter().map(CompositeField::json).collect()
+                            })
+                        })
+                        .collect(),
+                    None => Value::from(bytes_text(bytes)),
+                },
+                Err(_) => Value::Null,
+            },
+            DbType::JsonArray => {
+                <Vec<Option<serde_json::Value>> as sqlx::Decode<Postgres>>::decode(value)
+                    .map(|items| {
+                        items
+                            .into_iter()
+                            .map(|item| item.unwrap_or(Value::Null))
+                            .collect()
+                    })
+                    .unwrap_or(Value::Null)
+            }
+            DbType::TsVector | DbType::TsQuery => {
+                match <&[u8] as sqlx::Decode<Postgres>>::decode(value) {
+                    Ok(bytes) => {
+                        let text = if matches!(db_type, DbType::TsVector) {
+                            tsvector_to_text(bytes)
+                        } else {
+                            tsquery_to_text(bytes)
+                        };
+                        Value::from(text.unwrap_or_else(|| bytes_text(bytes)))
+                    }
+                    Err(_) => Value::Null,
+                }
+            }
+            DbType::PgChar => <i8 as sqlx::Decode<Postgres>>::decode(value)
+                .map(|byte| Value::from(pg_char_text(byte as u8)))
+                .unwrap_or(Value::Null),
+            _ => Value::Null,
+        }
+    }
+}
+
+/// Postgres' text for a `"char"`: `\ooo` octal above ASCII, empty for 0
+fn pg_char_text(byte: u8) -> String {
+    match byte {
+        0 => String::new(),
+        1..=127 => char::from(byte).to_string(),
+        _ => format!("\\{:03o}", byte),
+    }
+}
+
+/// A month counts as 30 days, like `EXTRACT(EPOCH FROM interval)`
+fn interval_seconds(interval: &PgInterval) -> f64 {
+    let days = interval.months as f64 * 30.0 + interval.days as f64;
+    days * 86400.0 + interval.microseconds as f64 / 1_000_000.0
+}
+
+/// Postgres' default `IntervalStyle` output
+fn format_interval(interval: &PgInterval) -> String {
+    let mut text = String::new();
+    // like Postgres, a positive part after a negative one is written with an explicit `+`
+    let mut negative = false;
+    for (value, unit) in [
+        (interval.months / 12, "year"),
+        (interval.months % 12, "mon"),
+        (interval.days, "day"),
+    ] {
+        if value == 0 {
+            continue;
+        }
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        let sign = if negative && value > 0 { "+" } else { "" };
+        let plural = if value == 1 { "" } else { "s" };
+        text.push_str(&format!("{}{} {}{}", sign, value, unit, plural));
+        negative = value < 0;
+    }
+    if interval.microseconds != 0 || text.is_empty() {
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        let sign = match (interval.microseconds < 0, negative) {
+            (true, _) => "-",
+            (false, true) => "+",
+            (false, false) => "",
+        };
+        let micros = interval.microseconds.unsigned_abs();
+        let seconds = micros / 1_000_000;
+        text.push_str(&format!(
+            "{}{:02}:{:02}:{:02}",
+            sign,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        ));
+        if !micros.is_multiple_of(1_000_000) {
+            let fraction = format!("{:06}", micros % 1_000_000);
+            text.push('.');
+            text.push_str(fraction.trim_end_matches('0'));
+        }
+    }
+    text
+}
+
+fn format_money(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    let cents = cents.unsigned_abs();
+    format!("{}{}.{:02}", sign, cents / 100, cents % 100)
+}
+
+impl DecodeExtra for MySql {
+    fn insert_extra(
+        state: LuaState,
+        table: &LuaTable,
+        column_name: &str,
+        value: <Self as Database>::ValueRef<'_>,
+        db_type: DbType,
+        rows_options: &RowsOptions,
+    ) -> Result<(), String> {
+        match db_type {
+            // MySQL prefixes the WKB with a 4 byte SRID
+            DbType::Geometry => match <&[u8] as sqlx::Decode<MySql>>::decode(value) {
+                Ok(bytes) if bytes.len() > 4 => {
+                    let srid = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    insert_geometry(state, table, column_name, srid, &bytes[4..], rows_options);
+                }
+                _ => {
+                    table.insert(column_name, LuaNil {});
+                }
+            },
+            _ => {
+                table.insert(column_name, LuaNil {});
+            }
+        }
+        Ok(())
+    }
+
+    fn extra_json(
+        value: <Self as Database>::ValueRef<'_>,
+        db_type: DbType,
+        rows_options: &RowsOptions,
+    ) -> serde_json::Value {
+        match (db_type, <&[u8] as sqlx::Decode<MySql>>::decode(value)) {
+            (DbType::Geometry, Ok(bytes)) if bytes.len() > 4 => {
+                let srid = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                geometry_json(srid, &bytes[4..], rows_options)
+            }
+            _ => serde_json::Value::Null,
+        }
+    }
+}
+
+fn insert_geometry(
+    state: LuaState,
+    table: &LuaTable,
+    column_name: &str,
+    srid: u32,
+    wkb: &[u8],
+    rows_options: &RowsOptions,
+) {
+    let wkt = wkb_to_wkt(wkb);
+    if !rows_options.geometry_srid {
+        table.insert(column_name, wkt);
+        return;
+    }
+    laux::lua_push(state, column_name);
+    let geometry = LuaTable::new(state, 0, 2);
+    geometry.insert("srid", srid);
+    geometry.insert("wkt", wkt);
+    table.insert_from_stack();
+}
+
+fn geometry_json(srid: u32, wkb: &[u8], rows_options: &RowsOptions) -> serde_json::Value {
+    let wkt = wkb_to_wkt(wkb);
+    if !rows_options.geometry_srid {
+        return serde_json::Value::from(wkt);
+    }
+    let mut geometry = serde_json::Map::new();
+    geometry.insert("srid".to_string(), srid.into());
+    geometry.insert("wkt".to_string(), wkt.into());
+    serde_json::Value::Object(geometry)
+}
+
+fn ewkb_srid(wkb: &[u8]) -> u32 {
+    let little = wkb.first() == Some(&1);
+    let read = |pos: usize| {
+        let bytes: [u8; 4] = wkb.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if little {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+    match read(1) {
+        Some(raw) if raw & 0x2000_0000 != 0 => read(5).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Unparseable geometries come back as `WKB:` and their hex bytes
+fn wkb_to_wkt(wkb: &[u8]) -> String {
+    let mut reader = WkbReader { buf: wkb, pos: 0 };
+    match reader.geometry(0) {
+        Some(wkt) if reader.pos == wkb.len() => wkt,
+        _ => {
+            let hex: String = wkb.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("WKB:{}", hex)
+        }
+    }
+}
+
+const WKB_MAX_DEPTH: usize = 64;
+
+struct WkbReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl WkbReader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.buf.get(self.pos..self.pos + N)?.try_into().ok()?;
+        self.pos += N;
+        Some(bytes)
+    }
+
+    fn u32(&mut self, little: bool) -> Option<u32> {
+        let bytes = self.take::<4>()?;
+        Some(if little {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn f64(&mut self, little: bool) -> Option<f64> {
+        let bytes = self.take::<8>()?;
+        Some(if little {
+            f64::from_le_bytes(bytes)
+        } else {
+            f64::from_be_bytes(bytes)
+        })
+    }
+
+    /// WKB writes an empty point as NaN coordinates
+    fn point(&mut self, little: bool, dims: usize) -> Option<String> {
+        let coords = (0..dims)
+            .map(|_| self.f64(little))
+            .collect::<Option<Vec<_>>>()?;
+        if coords.iter().all(|c| c.is_nan()) {
+            return Some("EMPTY".to_string());
+        }
+        let coords: Vec<String> = coords.iter().map(|c| c.to_string()).collect();
+        Some(format!("({})", coords.join(" ")))
+    }
+
+    fn point_list(&mut self, little: bool, dims: usize) -> Option<String> {
+        let n = self.u32(little)?;
+        if n == 0 {
+            return Some("EMPTY".to_string());
+        }
+        let points = (0..n)
+            .map(|_| {
+                (0..dims)
+                    .map(|_| self.f64(little).map(|c| c.to_string()))
+                    .collect::<Option<Vec<_>>>()
+                    .map(|coords| coords.join(" "))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(format!("({})", points.join(",")))
+    }
+
+    fn parts(
+        &mut self,
+        little: bool,
+        mut part: impl FnMut(&mut Self) -> Option<String>,
+    ) -> Option<String> {
+        let n = self.u32(little)?;
+        if n == 0 {
+            return Some("EMPTY".to_string());
+        }
+        let parts = (0..n).map(|_| part(self)).collect::<Option<Vec<_>>>()?;
+        Some(format!("({})", parts.join(",")))
+    }
+
+    fn geometry(&mut self, depth: usize) -> Option<String> {
+        if depth >= WKB_MAX_DEPTH {
+            return None;
+        }
+        let little = match self.take::<1>()? {
+            [0] => false,
+            [1] => true,
+            _ => return None,
+        };
+        let raw = self.u32(little)?;
+        // EWKB flags the dimensions and an embedded SRID in the high bits,
+        // ISO WKB adds 1000 (Z), 2000 (M) or 3000 (ZM) to the type code
+        let mut z = raw & 0x8000_0000 != 0;
+        let mut m = raw & 0x4000_0000 != 0;
+        if raw & 0x2000_0000 != 0 {
+            self.u32(little)?;
+        }
+        let code = raw & 0x0fff_ffff;
+        match code / 1000 {
+            0 => {}
+            1 => z = true,
+            2 => m = true,
+            3 => (z, m) = (true, true),
+            _ => return None,
+        }
+        let dims = 2 + z as usize + m as usize;
+
+        let (name, body) = match code % 1000 {
+            1 => ("POINT", self.point(little, dims)?),
+            2 => ("LINESTRING", self.point_list(little, dims)?),
+            3 => (
+                "POLYGON",
+                self.parts(little, |reader| reader.point_list(little, dims))?,
+            ),
+            4 => (
+                "MULTIPOINT",
+                self.parts(little, |reader| reader.member_body(depth + 1))?,
+            ),
+            5 => (
+                "MULTILINESTRING",
+                self.parts(little, |reader| reader.member_body(depth + 1))?,
+            ),
+            6 => (
+                "MULTIPOLYGON",
+                self.parts(little, |reader| reader.member_body(depth + 1))?,
+            ),
+            7 => (
+                "GEOMETRYCOLLECTION",
+                self.parts(little, |reader| reader.geometry(depth + 1))?,
+            ),
+            _ => return None,
+        };
+        let tag = match (z, m) {
+            (true, true) => " ZM",
+            (true, false) => " Z",
+            (false, true) => " M",
+            (false, false) => "",
+        };
+        Some(match (tag, body.as_str()) {
+            ("", body) if body.starts_with('(') => format!("{}{}", name, body),
+            (tag, body) => format!("{}{} {}", name, tag, body),
+        })
+    }
+
+    /// Members of multi geometries are full WKB geometries, WKT only keeps their body
+    fn member_body(&mut self, depth: usize) -> Option<String> {
+        let wkt = self.geometry(depth)?;
+        match wkt.find('(') {
+            Some(pos) => Some(wkt[pos..].to_string()),
+            None => Some("EMPTY".to_string()),
+        }
+    }
+}
+
+const TS_VAL: u8 = 1;
+const TS_OPR: u8 = 2;
+const TS_NOT: u8 = 1;
+const TS_AND: u8 = 2;
+const TS_OR: u8 = 3;
+const TS_PHRASE: u8 = 4;
+
+const TS_MAX_DEPTH: usize = 256;
+
+/// The binary form lists items in prefix order, right operand before the left
+enum TsNode {
+    /// A..D as 8, 4, 2, 1
+    Lexeme {
+        text: String,
+        weight: u8,
+        prefix: bool,
+    },
+    Not(Box<TsNode>),
+    Op {
+        oper: u8,
+        distance: i16,
+        left: Box<TsNode>,
+        right: Box<TsNode>,
+    },
+}
+
+impl TsNode {
+    fn priority(&self) -> u8 {
+        match self {
+            TsNode::Lexeme { .. } => 5,
+            TsNode::Not(_) => 4,
+            TsNode::Op {
+                oper: TS_PHRASE, ..
+            } => 3,
+            TsNode::Op { oper: TS_AND, .. } => 2,
+            TsNode::Op { .. } => 1,
+        }
+    }
+
+    fn count(&self) -> i32 {
+        match self {
+            TsNode::Lexeme { .. } => 1,
+            TsNode::Not(operand) => 1 + operand.count(),
+            TsNode::Op { left, right, .. } => 1 + left.count() + right.count(),
+        }
+    }
+
+    fn format(&self, out: &mut String) {
+        match self {
+            TsNode::Lexeme {
+                text,
+                weight,
+                prefix,
+            } => {
+                push_ts_lexeme(out, text);
+                if *prefix || *weight != 0 {
+                    out.push(':');
+                    if *prefix {
+                        out.push('*');
+                    }
+                    for (bit, letter) in [(8, 'A'), (4, 'B'), (2, 'C'), (1, 'D')] {
+                        if weight & bit != 0 {
+                            out.push(letter);
+                        }
+                    }
+                }
+            }
+            TsNode::Not(operand) => {
+                out.push('!');
+                operand.format_operand(out, self.priority(), false);
+            }
+            TsNode::Op {
+                oper,
+                distance,
+                left,
+                right,
+            } => {
+                left.format_operand(out, self.priority(), false);
+                match *oper {
+                    TS_AND => out.push_str(" & "),
+                    TS_OR => out.push_str(" | "),
+                    _ if *distance == 1 => out.push_str(" <-> "),
+                    _ => out.push_str(&format!(" <{}> ", distance)),
+                }
+                right.format_operand(out, self.priority(), true);
+            }
+        }
+    }
+
+    /// Parenthesized when binding looser than its operator, a right operand also when equal
+    fn format_operand(&self, out: &mut String, parent: u8, right: bool) {
+        let priority = self.priority();
+        if priority < parent || (right && priority == parent) {
+            out.push_str("( ");
+            self.format(out);
+            out.push_str(" )");
+        } else {
+            self.format(out);
+        }
+    }
+}
+
+fn push_ts_lexeme(out: &mut String, lexeme: &str) {
+    out.push('\'');
+    for c in lexeme.chars() {
+        if c == '\'' || c == '\\' {
+            out.push(c);
+        }
+        out.push(c);
+    }
+    out.push('\'');
+}
+
+/// Big endian binary wire formats: tsvector/tsquery, composites and their arrays, intervals
+struct BeReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BeReader<'a> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.buf.get(self.pos..self.pos + N)?.try_into().ok()?;
+        self.pos += N;
+        Some(bytes)
+    }
+
+    /// `Some(None)` for SQL NULL
+    fn sized(&mut self) -> Option<Option<&'a [u8]>> {
+        let len = i32::from_be_bytes(self.take::<4>()?);
+        let Ok(len) = usize::try_from(len) else {
+            return Some(None);
+        };
+        let value = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(Some(value))
+    }
+
+    fn cstring(&mut self) -> Option<String> {
+        let len = self.buf.get(self.pos..)?.iter().position(|b| *b == 0)?;
+        let text = String::from_utf8_lossy(&self.buf[self.pos..self.pos + len]).into_owned();
+        self.pos += len + 1;
+        Some(text)
+    }
+
+    fn tsquery_node(&mut self, depth: usize) -> Option<TsNode> {
+        if depth >= TS_MAX_DEPTH {
+            return None;
+        }
+        match self.take::<1>()? {
+            [TS_VAL] => {
+                let [weight, prefix] = self.take::<2>()?;
+                Some(TsNode::Lexeme {
+                    text: self.cstring()?,
+                    weight,
+                    prefix: prefix != 0,
+                })
+            }
+            [TS_OPR] => {
+                let [oper] = self.take::<1>()?;
+                if oper == TS_NOT {
+                    return Some(TsNode::Not(Box::new(self.tsquery_node(depth + 1)?)));
+                }
+                let distance = match oper {
+                    TS_PHRASE => i16::from_be_bytes(self.take::<2>()?),
+                    TS_AND | TS_OR => 0,
+                    _ => return None,
+                };
+                let right = self.tsquery_node(depth + 1)?;
+                let left = self.tsquery_node(depth + 1)?;
+                Some(TsNode::Op {
+                    oper,
+                    distance,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn tsvector_to_text(bytes: &[u8]) -> Option<String> {
+    let mut reader = BeReader { buf: bytes, pos: 0 };
+    let count = i32::from_be_bytes(reader.take::<4>()?);
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut entry = String::new();
+        push_ts_lexeme(&mut entry, &reader.cstring()?);
+        let positions = u16::from_be_bytes(reader.take::<2>()?);
+        for i in 0..positions {
+            let position = u16::from_be_bytes(reader.take::<2>()?);
+            entry.push(if i == 0 { ':' } else { ',' });
+            entry.push_str(&(position & 0x3fff).to_string());
+            match position >> 14 {
+                3 => entry.push('A'),
+                2 => entry.push('B'),
+                1 => entry.push('C'),
+                _ => {}
+            }
+        }
+        entries.push(entry);
+    }
+    (reader.pos == bytes.len()).then(|| entries.join(" "))
+}
+
+fn tsquery_to_text(bytes: &[u8]) -> Option<String> {
+    let mut reader = BeReader { buf: bytes, pos: 0 };
+    let count = i32::from_be_bytes(reader.take::<4>()?);
+    let mut text = String::new();
+    if count > 0 {
+        let root = reader.tsquery_node(0)?;
+        if root.count() != count {
+            return None;
+        }
+        root.format(&mut text);
+    }
+    (reader.pos == bytes.len()).then_some(text)
+}
+
+/// NULL elements are left as holes
+fn push_pg_array<T>(
+    state: LuaState,
+    items: &[Option<T>],
+    mut push: impl FnMut(&T) -> Result<(), String>,
+) -> Result<(), String> {
+    let array = LuaTable::new(state, items.len(), 0);
+    for (i, item) in items.iter().enumerate() {
+        if let Some(item) = item {
+            push(item)?;
+            array.rawseti(i + 1);
+        }
+    }
+    Ok(())
+}
+
+enum CompositeField<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bytes(&'a [u8]),
+}
+
+impl CompositeField<'_> {
+    fn push(&self, state: LuaState) {
+        match self {
+            CompositeField::Null => laux::lua_pushlightuserdata(state, std::ptr::null_mut()),
+            CompositeField::Bool(value) => laux::lua_push(state, *value),
+            CompositeField::Int(value) => laux::lua_push(state, *value),
+            CompositeField::Float(value) => laux::lua_push(state, *value),
+            CompositeField::Text(value) => laux::lua_push(state, value.as_str()),
+            CompositeField::Bytes(value) => laux::lua_push(state, *value),
+        }
+    }
+
+    fn json(self) -> serde_json::Value {
+        match self {
+            CompositeField::Null => serde_json::Value::Null,
+            CompositeField::Bool(value) => value.into(),
+            CompositeField::Int(value) => value.into(),
+            CompositeField::Float(value) => value.into(),
+            CompositeField::Text(value) => value.into(),
+            CompositeField::Bytes(value) => bytes_text(value).into(),
+        }
+    }
+}
+
+/// Multi-dimensional arrays are flattened
+fn parse_composite_array(bytes: &[u8]) -> Option<Vec<Option<Vec<CompositeField<'_>>>>> {
+    let mut reader = BeReader { buf: bytes, pos: 0 };
+    let dims = usize::try_from(i32::from_be_bytes(reader.take::<4>()?)).ok()?;
+    // has-NULLs flag and element type OID
+    reader.take::<8>()?;
+    let mut count = usize::from(dims > 0);
+    for _ in 0..dims {
+        let len = usize::try_from(i32::from_be_bytes(reader.take::<4>()?)).ok()?;
+        reader.take::<4>()?;
+        count = count.checked_mul(len)?;
+    }
+    // every element takes at least its 4 byte length
+    let mut items = Vec::with_capacity(count.min(bytes.len() / 4));
+    for _ in 0..count {
+        items.push(match reader.sized()? {
+            Some(element) => Some(parse_composite(element)?),
+            None => None,
+        });
+    }
+    (reader.pos == bytes.len()).then_some(items)
+}
+
+fn parse_composite(bytes: &[u8]) -> Option<Vec<CompositeField<'_>>> {
+    let mut reader = BeReader { buf: bytes, pos: 0 };
+    let count = usize::try_from(i32::from_be_bytes(reader.take::<4>()?)).ok()?;
+    let mut fields = Vec::with_capacity(count.min(bytes.len() / 8));
+    for _ in 0..count {
+        let oid = u32::from_be_bytes(reader.take::<4>()?);
+        fields.push(match reader.sized()? {
+            Some(value) => composite_field(oid, value),
+            None => CompositeField::Null,
+        });
+    }
+    (reader.pos == bytes.len()).then_some(fields)
+}
+
+/// 2000-01-01, where Postgres' binary dates and timestamps count from
+const PG_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+fn pg_timestamp(micros: i64) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::from_timestamp_micros(micros.checked_add(PG_EPOCH_MICROS)?)
+}
+
+fn composite_field(oid: u32, bytes: &[u8]) -> CompositeField<'_> {
+    let field = match oid {
+        // bool
+        16 => bytes.first().map(|byte| CompositeField::Bool(*byte != 0)),
+        // int2, int4, int8
+        21 => bytes
+            .try_into()
+            .ok()
+            .map(|b| CompositeField::Int(i16::from_be_bytes(b).into())),
+        23 => bytes
+            .try_into()
+            .ok()
+            .map(|b| CompositeField::Int(i32::from_be_bytes(b).into())),
+        20 => bytes
+            .try_into()
+            .ok()
+            .map(|b| CompositeField::Int(i64::from_be_bytes(b))),
+        // float4, float8
+        700 => bytes
+            .try_into()
+            .ok()
+            .map(|b| CompositeField::Float(f32::from_be_bytes(b).into())),
+        701 => bytes
+            .try_into()
+            .ok()
+            .map(|b| CompositeField::Float(f64::from_be_bytes(b))),
+        // text, varchar, bpchar, name, json
+        25 | 1043 | 1042 | 19 | 114 => std::str::from_utf8(bytes)
+            .ok()
+            .map(|text| CompositeField::Text(text.to_string())),
+        // jsonb, a version byte before the text
+        3802 => match bytes.split_first() {
+            Some((1, text)) => std::str::from_utf8(text)
+                .ok()
+                .map(|text| CompositeField::Text(text.to_string())),
+            _ => None,
+        },
+        // uuid
+        2950 => Uuid::from_slice(bytes)
+            .ok()
+            .map(|uuid| CompositeField::Text(uuid.to_string())),
+        // date, days since the epoch
+        1082 => bytes
+            .try_into()
+            .ok()
+            .and_then(|b| {
+                pg_timestamp(i64::from(i32::from_be_bytes(b)).checked_mul(86_400_000_000)?)
+            })
+            .map(|dt| CompositeField::Text(dt.format("%Y-%m-%d").to_string())),
+        // time, microseconds since midnight
+        1083 => bytes
+            .try_into()
+            .ok()
+            .map(|b| NaiveTime::MIN + chrono::Duration::microseconds(i64::from_be_bytes(b)))
+            .map(|time| CompositeField::Text(format_time(time))),
+        // timestamp
+        1114 => bytes
+            .try_into()
+            .ok()
+            .and_then(|b| pg_timestamp(i64::from_be_bytes(b)))
+            .map(|dt| CompositeField::Text(dt.format("%Y-%m-%d %H:%M:%S%.f").to_string())),
+        // timestamptz, printed with its UTC offset like Postgres does
+        1184 => bytes
+            .try_into()
+            .ok()
+            .and_then(|b| pg_timestamp(i64::from_be_bytes(b)))
+            .map(|dt| CompositeField::Text(dt.format("%Y-%m-%d %H:%M:%S%.f%:::z").to_string())),
+        // interval
+        1186 => {
+            binary_interval(bytes).map(|interval| CompositeField::Text(format_interval(&interval)))
+        }
+        _ => None,
+    };
+    field.unwrap_or(CompositeField::Bytes(bytes))
+}
+
+fn binary_interval(bytes: &[u8]) -> Option<PgInterval> {
+    let mut reader = BeReader { buf: bytes, pos: 0 };
+    let interval = PgInterval {
+        microseconds: i64::from_be_bytes(reader.take::<8>()?),
+        days: i32::from_be_bytes(reader.take::<4>()?),
+        months: i32::from_be_bytes(reader.take::<4>()?),
+    };
+    (reader.pos == bytes.len()).then_some(interval)
+}
+
+impl DecodeExtra for Sqlite {
+    /// Untyped columns (views, expressions) decode by the value's storage class
+    fn overrides(db_type: DbType) -> bool {
+        matches!(db_type, DbType::Bool | DbType::Unknown | DbType::Null)
+    }
+
+    fn insert_extra(
+        _state: LuaState,
+        table: &LuaTable,
+        column_name: &str,
+        value: <Self as Database>::ValueRef<'_>,
+        db_type: DbType,
+        rows_options: &RowsOptions,
+    ) -> Result<(), String> {
+        match db_type {
+            DbType::Bool => match <i64 as sqlx::Decode<Sqlite>>::decode(value) {
+                Ok(0) => {
+                    table.insert(column_name, false);
+                }
+                Ok(1) => {
+                    table.insert(column_name, true);
+                }
+                Ok(v) => {
+                    if rows_options.non_boolean.first() {
+                        moon_log(
+                            0,
+                            LOG_LEVEL_WARN,
+                            format!(
+                                "SQLite BOOLEAN column '{}' holds non 0/1 values such as {}, decoded as integers",
+                                column_name, v
+                            ),
+                        );
+                    }
+                    table.insert(column_name, v);
+                }
+                Err(_) => {
+                    table.insert(column_name, LuaNil {});
+                }
+            },
+            DbType::Unknown | DbType::Null => insert_storage_class(table, column_name, value),
+            _ => {
+                table.insert(column_name, LuaNil {});
+            }
+        }
+        Ok(())
+    }
+
+    fn extra_json(
+        value: <Self as Database>::ValueRef<'_>,
+        db_type: DbType,
+        _rows_options: &RowsOptions,
+    ) -> serde_json::Value {
+        use serde_json::Value;
+
+        let storage_class = value.type_info().name().to_string();
+        match (db_type, storage_class.as_str()) {
+            (DbType::Bool, _) => match <i64 as sqlx::Decode<Sqlite>>::decode(value) {
+                Ok(0) => Value::from(false),
+                Ok(1) => Value::from(true),
+                Ok(v) => Value::from(v),
+                Err(_) => Value::Null,
+            },
+            (DbType::Unknown | DbType::Null, "INTEGER") => {
+                <i64 as sqlx::Decode<Sqlite>>::decode(value).map_or(Value::Null, Value::from)
+            }
+            (DbType::Unknown | DbType::Null, "REAL") => {
+                <f64 as sqlx::Decode<Sqlite>>::decode(value).map_or(Value::Null, Value::from)
+            }
+            (DbType::Unknown | DbType::Null, "TEXT") => {
+                <&str as sqlx::Decode<Sqlite>>::decode(value).map_or(Value::Null, Value::from)
+            }
+            (DbType::Unknown | DbType::Null, "BLOB") => {
+                <&[u8] as sqlx::Decode<Sqlite>>::decode(value)
+                    .map_or(Value::Null, |bytes| bytes_text(bytes).into())
+            }
+            _ => Value::Null,
+        }
+    }
+}
+
+fn insert_storage_class(table: &LuaTable, column_name: &str, value: SqliteValueRef<'_>) {
+    let storage_class = value.type_info().name().to_string();
+    match storage_class.as_str() {
+        "INTEGER" => match <i64 as sqlx::Decode<Sqlite>>::decode(value) {
+            Ok(v) => {
+                table.insert(column_name, v);
+            }
+            Err(_) => {
+                table.insert(column_name, LuaNil {});
+            }
+        },
+        "REAL" => match <f64 as sqlx::Decode<Sqlite>>::decode(value) {
+            Ok(v) => {
+                table.insert(column_name, v);
+            }
+            Err(_) => {
+                table.insert(column_name, LuaNil {});
+            }
+        },
+        "TEXT" => match <&str as sqlx::Decode<Sqlite>>::decode(value) {
+            Ok(v) => {
+                table.insert(column_name, v);
+            }
+            Err(_) => {
+                table.insert(column_name, LuaNil {});
+            }
+        },
+        "BLOB" => match <&[u8] as sqlx::Decode<Sqlite>>::decode(value) {
+            Ok(v) => {
+                table.insert(column_name, v);
+            }
+            Err(_) => {
+                table.insert(column_name, LuaNil {});
+            }
+        },
+        _ => {
+            table.insert(column_name, LuaNil {});
+        }
+    }
+}
+
+/// JSON text bound as `json`, serde values go out as `jsonb`
+struct PgJson(String);
+
+impl sqlx::Type<Postgres> for PgJson {
+    fn type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("json")
+    }
+}
+
+impl sqlx::Encode<'_, Postgres> for PgJson {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        <&str as sqlx::Encode<Postgres>>::encode_by_ref(&self.0.as_str(), buf)
+    }
+}
+
+/// Untyped NULL, so the server infers the type from the column
+struct PgNull;
+
+impl sqlx::Type<Postgres> for PgNull {
+    fn type_info() -> PgTypeInfo {
+        PgTypeInfo::with_oid(Oid(0))
+    }
+}
+
+impl sqlx::Encode<'_, Postgres> for PgNull {
+    fn encode_by_ref(&self, _buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        Ok(IsNull::Yes)
+    }
+}
+
+enum DatabaseRequest {
+    Query(u32, i64, DatabaseQuery), //owner, session, QueryBuilder
+    Transaction(u32, i64, Vec<DatabaseQuery>, u32, u32), //owner, session, Vec<QueryBuilder>, attempt, deadlock attempts
+    Begin(u32, i64),                                     //owner, session
+    Describe(u32, i64, String),                          //owner, session, sql
+    EstimateCount(u32, i64, String),                     //owner, session, table
+    Recycle(u32, i64),                                   //owner, session
+    Export(u32, i64, ExportRequest),                     //owner, session, export
+    Close(),
+    CancelOwner(u32), //owner
+}
+
+impl DatabaseRequest {
+    fn owner(&self) -> Option<u32> {
+        match self {
+            DatabaseRequest::Query(owner, _, _)
+            | DatabaseRequest::Transaction(owner, ..)
+            | DatabaseRequest::Begin(owner, _)
+            | DatabaseRequest::Describe(owner, _, _)
+            | DatabaseRequest::EstimateCount(owner, _, _)
+            | DatabaseRequest::Recycle(owner, _)
+            | DatabaseRequest::Export(owner, _, _) => Some(*owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct DatabaseConnection {
+    tx: mpsc::Sender<DatabaseRequest>,
+    counter: Arc<AtomicI64>,
+    stats: Arc<ConnectionStats>,
+    /// `CancelOwner` markers sent but not yet seen by the handler
+    cancels: Arc<AtomicUsize>,
+    raise_errors: bool,
+    numbered_params: bool,
+    no_json_autodetect: bool,
+    max_inflight: Option<i64>,
+    max_sql_bytes: Option<usize>,
+    composite_types: CompositeTypes,
+    info: Arc<ConnectionInfo>,
+}
+
+/// Never holds the password
+struct ConnectionInfo {
+    backend: &'static str,
+    username: String,
+    database: String,
+    hosts: Vec<String>,
+    connect_timeout_ms: u64,
+    max_connections: u32,
+    min_connections: u32,
+    acquire_timeout_ms: u64,
+    idle_timeout_ms: Option<u64>,
+    max_lifetime_ms: Option<u64>,
+}
+
+impl ConnectionInfo {
+    fn new(pool: &DatabasePool, failover: &Failover) -> Self {
+        fn from_options<DB: Database>(
+            backend: &'static str,
+            options: &PoolOptions<DB>,
+            failover: &Failover,
+        ) -> ConnectionInfo {
+            let url = failover.active_url();
+            let (username, database) = if backend == "sqlite" {
+                let path = url.trim_start_matches("sqlite://");
+                (String::new(), path.split('?').next().unwrap_or_default())
+            } else {
+                let (prefix, _, rest) = split_host(url);
+                let userinfo = prefix
+                    .find("://")
+                    .map_or("", |pos| prefix[pos + 3..].trim_end_matches('@'));
+                let username = userinfo.split(':').next().unwrap_or_default();
+                let database = rest
+                    .trim_start_matches('/')
+                    .split('?')
+                    .next()
+                    .unwrap_or_default();
+                (username.to_string(), database)
+            };
+
+            ConnectionInfo {
+                backend,
+                username,
+                database: database.to_string(),
+                hosts: failover
+                    .urls
+                    .iter()
+                    .map(|url| split_host(url).1.to_string())
+                    .collect(),
+                connect_timeout_ms: failover.timeout.as_millis() as u64,
+                max_connections: options.get_max_connections(),
+                min_connections: options.get_min_connections(),
+                acquire_timeout_ms: options.get_acquire_timeout().as_millis() as u64,
+                idle_timeout_ms: options.get_idle_timeout().map(|d| d.as_millis() as u64),
+                max_lifetime_ms: options.get_max_lifetime().map(|d| d.as_millis() as u64),
+            }
+        }
+
+        match pool {
+            DatabasePool::MySql(pool) => from_options("mysql", pool.options(), failover),
+            DatabasePool::Postgres(pool) => from_options("postgres", pool.options(), failover),
+            DatabasePool::Sqlite(pool) => from_options("sqlite", pool.options(), failover),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ConnectionStats {
+    throttle_delay_ms: AtomicU64,
+    throttled: AtomicU64,
+    active_host: std::sync::Mutex<String>,
+    peak_pending: AtomicI64,
+    unhealthy: AtomicBool,
+    last_error: std::sync::Mutex<Option<String>>,
+    acquire_waiters: AtomicI64,
+    server_version: std::sync::Mutex<Option<ServerVersion>>,
+    trace: AtomicBool,
+    measure_send: AtomicBool,
+    send: std::sync::Mutex<SendStats>,
+}
+
+#[derive(Default)]
+struct SendStats {
+    responses: u64,
+    rows: u64,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+struct ServerVersion {
+    text: String,
+    /// `major * 10000 + minor * 100 + patch`, Postgres' server_version_num only before 10
+    number: i64,
+}
+
+impl ServerVersion {
+    fn parse(banner: &str) -> Option<Self> {
+        let text: &str = banner
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .next()?
+            .trim_end_matches('.');
+        let mut parts = text.split('.').map(|part| part.parse::<i64>().ok());
+        let major = parts.next().flatten()?;
+        let minor = parts.next().flatten().unwrap_or(0);
+        let patch = parts.next().flatten().unwrap_or(0);
+        Some(ServerVersion {
+            text: text.to_string(),
+            number: major * 10000 + minor * 100 + patch,
+        })
+    }
+
+    async fn refresh(pool: &DatabasePool, stats: &ConnectionStats, owner: u32, label: &str) {
+        let version = match pool.server_version().await {
+            Ok(banner) => ServerVersion::parse(&banner),
+            Err(err) => {
+                moon_log(
+                    owner,
+                    LOG_LEVEL_WARN,
+                    format!("Database '{}' server version query failed: {}", label, err),
+                );
+                None
+            }
+        };
+        *stats.server_version.lock().unwrap() = version;
+    }
+}
+
+impl ConnectionStats {
+    /// Query errors such as bad SQL don't make the server unhealthy
+    fn record<T>(&self, res: &Result<T, sqlx::Error>) {
+        match res {
+            Ok(_) => self
+                .unhealthy
+                .store(false, std::sync::atomic::Ordering::Release),
+            Err(err) if is_connection_error(err) => {
+                *self.last_error.lock().unwrap() = Some(err.to_string());
+                self.unhealthy
+                    .store(true, std::sync::atomic::Ordering::Release);
+            }
+            Err(_) => {}
+        }
+    }
+
+    fn send(&self, protocol_type: u8, owner: u32, session: i64, response: DatabaseResponse) {
+        if session == 0 || !self.measure_send.load(std::sync::atomic::Ordering::Relaxed) {
+            moon_send(protocol_type, owner, session, response);
+            return;
+        }
+        let (rows, bytes) = response.payload_size();
+        let start = Instant::now();
+        moon_send(protocol_type, owner, session, response);
+        let elapsed = start.elapsed();
+
+        let mut send = self.send.lock().unwrap();
+        send.responses += 1;
+        send.rows += rows as u64;
+        send.bytes += bytes as u64;
+        send.elapsed += elapsed;
+    }
+}
+
+/// Token bucket allowing bursts of one second worth of queries
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(qps: u32) -> Self {
+        Self {
+            rate: qps as f64,
+            tokens: qps as f64,
+            last: Instant::now(),
+        }
+    }
+
+    async fn acquire(&mut self) -> Duration {
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Duration::ZERO;
+        }
+
+        let delay = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+        tokio::time::sleep(delay).await;
+        self.tokens = 0.0;
+        self.last = Instant::now();
+        delay
+    }
+}
+
+enum DatabaseResponse {
+    Connect,
+    PgRows(QueryRows<PgRow>),
+    MysqlRows(QueryRows<MySqlRow>),
+    SqliteRows(QueryRows<SqliteRow>),
+    Error(sqlx::Error),
+    Timeout(String),
+    /// Affected rows per statement, then the rows of the labeled statements
+    Transaction(Vec<u64>, Vec<(String, DatabaseResponse)>),
+    Session(TransactionSession),
+    Describe(Vec<ColumnDescription>),
+    Recycled(u64),
+    Exported(u64),
+    Estimated(i64),
+    MysqlResultSets(Vec<QueryRows<MySqlRow>>),
+}
+
+struct ColumnDescription {
+    name: String,
+    type_name: String,
+    db_type: DbType,
+    /// `None` when the backend can't tell
+    nullable: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum QueryParams {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Json(serde_json::Value),
+    Bytes(Vec<u8>),
+    JsonText(serde_json::Value),
+    Jsonb(serde_json::Value),
+    Interval(f64),
+    IntervalArray(Vec<f64>),
+    BytesArray(Vec<Vec<u8>>),
+    BoolArray(Vec<Option<bool>>),
+    Time(NaiveTime),
+    Record {
+        literal: String,
+        type_name: String,
+        fields: usize,
+    },
+    TsQuery(String),
+    Null,
+    Decimal(Decimal),
+}
+
+impl QueryParams {
+    fn kind(&self) -> &'static str {
+        match self {
+            QueryParams::Bool(_) => "bool",
+            QueryParams::Int(_) => "int",
+            QueryParams::Float(_) => "float",
+            QueryParams::Text(_) => "text",
+            QueryParams::Json(_) => "json",
+            QueryParams::Bytes(_) => "bytes",
+            QueryParams::JsonText(_) => "json",
+            QueryParams::Jsonb(_) => "jsonb",
+            QueryParams::Interval(_) => "interval",
+            QueryParams::IntervalArray(_) => "interval[]",
+            QueryParams::BytesArray(_) => "bytea[]",
+            QueryParams::BoolArray(_) => "bool[]",
+            QueryParams::Time(_) => "time",
+            QueryParams::Record { .. } => "record",
+            QueryParams::TsQuery(_) => "tsquery",
+            QueryParams::Null => "null",
+            QueryParams::Decimal(_) => "decimal",
+        }
+    }
+}
+
+struct QueryRows<R> {
+    rows: Arc<Vec<R>>,
+    decode: Arc<DecodeOptions>,
+    options: RowsOptions,
+    elapsed: Duration,
+    affected: Option<u64>,
+    truncated: bool,
+}
+
+impl<R> Clone for QueryRows<R> {
+    fn clone(&self) -> Self {
+        Self {
+            rows: self.rows.clone(),
+            decode: self.decode.clone(),
+            options: self.options.clone(),
+            elapsed: self.elapsed,
+            affected: self.affected,
+            truncated: self.truncated,
+        }
+    }
+}
+
+impl DatabaseResponse {
+    fn share(&self) -> Option<DatabaseResponse> {
+        match self {
+            DatabaseResponse::PgRows(rows) => Some(DatabaseResponse::PgRows(rows.clone())),
+            DatabaseResponse::MysqlRows(rows) => Some(DatabaseResponse::MysqlRows(rows.clone())),
+            DatabaseResponse::SqliteRows(rows) => Some(DatabaseResponse::SqliteRows(rows.clone())),
+            _ => None,
+        }
+    }
+
+    fn elapsed_ms(&self) -> Option<f64> {
+        let (options, elapsed) = match self {
+            DatabaseResponse::PgRows(rows) => (&rows.options, rows.elapsed),
+            DatabaseResponse::MysqlRows(rows) => (&rows.options, rows.elapsed),
+            DatabaseResponse::SqliteRows(rows) => (&rows.options, rows.elapsed),
+            _ => return None,
+        };
+        options
+            .with_timing
+            .then_some(elapsed.as_secs_f64() * 1000.0)
+    }
+
+    /// `Some(unordered)` when `max_rows` cut the rows short
+    fn truncated(&self) -> Option<bool> {
+        let (options, truncated) = match self {
+            DatabaseResponse::PgRows(rows) => (&rows.options, rows.truncated),
+            DatabaseResponse::MysqlRows(rows) => (&rows.options, rows.truncated),
+            DatabaseResponse::SqliteRows(rows) => (&rows.options, rows.truncated),
+            _ => return None,
+        };
+        truncated.then_some(options.unordered)
+    }
+
+    fn payload_size(&self) -> (usize, usize) {
+        match self {
+            DatabaseResponse::PgRows(rows) => (rows.rows.len(), rows_bytes::<Postgres>(&rows.rows)),
+            DatabaseResponse::MysqlRows(rows) => (rows.rows.len(), rows_bytes::<MySql>(&rows.rows)),
+            DatabaseResponse::SqliteRows(rows) => {
+                (rows.rows.len(), rows_bytes::<Sqlite>(&rows.rows))
+            }
+            DatabaseResponse::MysqlResultSets(sets) => sets.iter().fold((0, 0), |(n, b), rows| {
+                (n + rows.rows.len(), b + rows_bytes::<MySql>(&rows.rows))
+            }),
+            DatabaseResponse::Transaction(_, labeled) => {
+                labeled.iter().fold((0, 0), |(n, b), (_, response)| {
+                    let (rows, bytes) = response.payload_size();
+                    (n + rows, b + bytes)
+                })
+            }
+            _ => (0, 0),
+        }
+    }
+
+    fn rows_options(&self) -> Option<&RowsOptions> {
+        match self {
+            DatabaseResponse::PgRows(rows) => Some(&rows.options),
+            DatabaseResponse::MysqlRows(rows) => Some(&rows.options),
+            DatabaseResponse::SqliteRows(rows) => Some(&rows.options),
+            _ => None,
+        }
+    }
+}
+
+/// Values that won't read as bytes count as 8
+fn rows_bytes<'a, DB: Database>(rows: &'a [<DB as Database>::Row]) -> usize
+where
+    usize: ColumnIndex<<DB as Database>::Row>,
+    &'a [u8]: sqlx::Decode<'a, DB>,
+{
+    rows.iter()
+        .map(|row| {
+            (0..row.len())
+                .filter_map(|index| row.try_get_raw(index).ok())
+                .filter(|value| !value.is_null())
+                .map(|value| <&[u8] as sqlx::Decode<DB>>::decode(value).map_or(8, <[u8]>::len))
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+struct RowsOptions {
+    stream: bool,
+    columns: Option<Vec<String>>,
+    with_timing: bool,
+    json_as_table: bool,
+    json_numbers: JsonNumbers,
+    empty_as_nil: Option<bool>,
+    uuid_columns: Vec<String>,
+    uuid_swap: bool,
+    encodings: Vec<(String, &'static Encoding)>,
+    flat: bool,
+    defaults: Vec<(String, QueryParams)>,
+    nulls: NullCells,
+    geometry_srid: bool,
+    with_count: bool,
+    idempotent: bool,
+    result_sets: bool,
+    label: Option<String>,
+    max_rows: Option<usize>,
+    unordered: bool,
+    non_boolean: DecodeWarned,
+}
+
+/// Not part of the query: a clone starts unset and any two compare equal
+#[derive(Default, Debug)]
+struct DecodeWarned(AtomicBool);
+
+impl DecodeWarned {
+    fn first(&self) -> bool {
+        !self.0.swap(true, std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+impl Clone for DecodeWarned {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl PartialEq for DecodeWarned {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+enum NullCells {
+    #[default]
+    Omit,
+    JsonNull,
+    Sentinel(String),
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+enum JsonNumbers {
+    /// Integers fitting i64 stay integers, larger ones become strings
+    #[default]
+    Preserve,
+    Float,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DatabaseQuery {
+    sql: String,
+    binds: Vec<QueryParams>,
+    options: RowsOptions,
+}
+
+fn is_sqlite_busy(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .and_then(|db_err| db_err.try_downcast_ref::<SqliteError>())
+        .and_then(|db_err| db_err.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED))
+}
+
+struct SlowTransaction<'a> {
+    threshold: Duration,
+    owner: u32,
+    label: &'a str,
+}
+
+impl SlowTransaction<'_> {
+    fn check(&self, begun: Instant, statements: usize, committed: bool) {
+        let elapsed = begun.elapsed();
+        if elapsed < self.threshold {
+            return;
+        }
+        moon_log(
+            self.owner,
+            LOG_LEVEL_WARN,
+            format!(
+                "Database '{}' slow transaction: {} statements took {} ms from begin to {} (slow_transaction_ms {})",
+                self.label,
+                statements,
+                elapsed.as_millis(),
+                if committed { "commit" } else { "rollback" },
+                self.threshold.as_millis()
+            ),
+        );
+    }
+}
+
+fn is_deadlock(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .and_then(error_category)
+        .is_some_and(|category| matches!(category, "deadlock" | "serialization_failure"))
+}
+
+/// Doubles from 20ms, with jitter so the colliding transactions don't retry in lockstep
+fn deadlock_backoff(attempt: u32) -> Duration {
+    let base = 20u64 << attempt.saturating_sub(1).min(6);
+    Duration::from_millis(base + rand::random_range(0..=base))
+}
+
+/// Requests of an owner whose deadlocked transaction waits out its backoff stay queued behind it
+fn next_request(
+    pending: &mut VecDeque<DatabaseRequest>,
+    retry_due: &mut HashMap<u32, Instant>,
+) -> Option<DatabaseRequest> {
+    if retry_due.is_empty() {
+        return pending.pop_front();
+    }
+    let now = Instant::now();
+    retry_due.retain(|_, due| *due > now);
+    let pos = pending.iter().position(|op| {
+        op.owner()
+            .is_none_or(|owner| !retry_due.contains_key(&owner))
+    })?;
+    pending.remove(pos)
+}
+
+fn error_category(db_err: &dyn DatabaseError) -> Option<&'static str> {
+    // MySQL reports deadlocks and lock wait timeouts under generic SQLSTATEs
+    if let Some(mysql_err) = db_err.try_downcast_ref::<MySqlDatabaseError>() {
+        match mysql_err.number() {
+            1213 => return Some("deadlock"),
+            1205 => return Some("lock_timeout"),
+            _ => {}
+        }
+    }
+    let category = match db_err.code().as_deref() {
+        Some("23505") => "unique_violation",
+        Some("23503") => "foreign_key_violation",
+        Some("23502") => "not_null_violation",
+        Some("23514") => "check_violation",
+        Some("23P01") => "exclusion_violation",
+        Some("40P01") => "deadlock",
+        Some("40001") => "serialization_failure",
+        Some("55P03") => "lock_timeout",
+        Some("57014") => "query_canceled",
+        Some("42601") => "syntax_error",
+        Some("42P01") => "undefined_table",
+        Some("42703") => "undefined_column",
+        Some("42501") => "insufficient_privilege",
+        Some("22001") => "string_data_right_truncation",
+        Some("22003") => "numeric_value_out_of_range",
+        Some("22012") => "division_by_zero",
+        Some("22P02") => "invalid_text_representation",
+        Some("53300") => "too_many_connections",
+        Some("28000" | "28P01") => "invalid_authorization",
+        Some(code) if code.starts_with("08") => "connection_exception",
+        // MySQL and SQLite constraint errors carry no specific SQLSTATE, sqlx classifies them
+        _ => match db_err.kind() {
+            ErrorKind::UniqueViolation => "unique_violation",
+            ErrorKind::ForeignKeyViolation => "foreign_key_violation",
+            ErrorKind::NotNullViolation => "not_null_violation",
+            ErrorKind::CheckViolation => "check_violation",
+            _ => return None,
+        },
+    };
+    Some(category)
+}
+
+/// Logs show `host` only, the url may hold a password expanded from `${VAR}`
+async fn handle_result(
+    host: &str,
+    failed_times: &mut i32,
+    conn: &DatabaseConnection,
+    protocol_type: u8,
+    owner: u32,
+    session: i64,
+    res: Result<DatabaseResponse, sqlx::Error>,
+) -> bool {
+    match res {
+        Ok(rows) => {
+            conn.stats.send(protocol_type, owner, session, rows);
+            if *failed_times > 0 {
+                moon_log(
+                    owner,
+                    LOG_LEVEL_INFO,
+                    format!("Database '{}' recover from error. Retry success.", host),
+                );
+            }
+            conn.counter
+                .fetch_sub(1, std::sync::atomic::Ordering::Release);
+            false
+        }
+        Err(err) => {
+            if is_sqlite_busy(&err) && *failed_times < SQLITE_BUSY_MAX_RETRIES {
+                *failed_times += 1;
+                tokio::time::sleep(Duration::from_millis(10 << *failed_times)).await;
+                return true;
+            }
+
+            if session != 0 {
+                conn.stats
+                    .send(protocol_type, owner, session, DatabaseResponse::Error(err));
+                conn.counter
+                    .fetch_sub(1, std::sync::atomic::Ordering::Release);
+                false
+            } else if db_shutting_down() {
+                // an unreachable database would otherwise keep the drain retrying forever
+                moon_log(
+                    owner,
+                    LOG_LEVEL_ERROR,
+                    format!(
+                        "Database '{}' error: '{:?}'. Dropped, shutting down.",
+                        host,
+                        err.to_string()
+                    ),
+                );
+                conn.counter
+                    .fetch_sub(1, std::sync::atomic::Ordering::Release);
+                false
+            } else {
+                if *failed_times > 0 {
+                    moon_log(
+                        owner,
+                        LOG_LEVEL_ERROR,
+                        format!(
+                            "Database '{}' error: '{:?}'. Will retry.",
+                            host,
+                            err.to_string()
+                        ),
+                    );
+                }
+                *failed_times += 1;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                true
+            }
+        }
+    }
+}
+
+fn split_host(url: &str) -> (&str, &str, &str) {
+    let start = url.find("://").map_or(0, |pos| pos + 3);
+    let end = url[start..]
+        .find(['/', '?'])
+        .map_or(url.len(), |pos| start + pos);
+    let host = url[start..end]
+        .rfind('@')
+        .map_or(start, |pos| start + pos + 1);
+    (&url[..host], &url[host..end], &url[end..])
+}
+
+/// `PoolTimedOut` isn't one: every pooled connection is busy, the host isn't down
+fn is_connection_error(err: &sqlx::Error) -> bool {
+    matches!(
+        err,
+        sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::Protocol(_)
+            | sqlx::Error::PoolClosed
+    )
+}
+
+/// The pool replaces a broken connection, so the request can run again
+fn is_connection_reset(err: &sqlx::Error) -> bool {
+    matches!(err, sqlx::Error::Io(_) | sqlx::Error::Protocol(_))
+}
+
+struct Failover {
+    urls: Vec<String>,
+    active: usize,
+    timeout: Duration,
+    options: ConnectOptions,
+    label: String,
+}
+
+impl Failover {
+    fn new(database_url: &str, timeout: Duration, options: ConnectOptions, label: &str) -> Self {
+        let urls = if options.hosts.is_empty() || database_url.starts_with("sqlite://") {
+            vec![database_url.to_string()]
+        } else {
+            let (prefix, _, rest) = split_host(database_url);
+            options
+                .hosts
+                .iter()
+                .map(|host| format!("{}{}{}", prefix, host, rest))
+                .collect()
+        };
+
+        Self {
+            urls,
+            active: 0,
+            timeout,
+            options,
+            label: label.to_string(),
+        }
+    }
+
+    fn active_url(&self) -> &str {
+        &self.urls[self.active]
+    }
+
+    fn active_host(&self) -> &str {
+        split_host(self.active_url()).1
+    }
+
+    async fn connect_host(&self, index: usize, owner: u32) -> Result<DatabasePool, sqlx::Error> {
+        let mut attempt = 0;
+        loop {
+            let pool = DatabasePool::connect(
+                &self.urls[index],
+                self.timeout,
+                &self.options,
+                &self.label,
+                owner,
+            )
+            .await?;
+
+            let Some(sql) = self.options.validation_query.as_deref() else {
+                return Ok(pool);
+            };
+
+            match pool.validate(sql).await {
+                Ok(()) => return Ok(pool),
+                Err(err) => {
+                    attempt += 1;
+                    moon_log(
+                        owner,
+                        LOG_LEVEL_WARN,
+                        format!(
+                            "Database '{}' validation query failed on '{}' (attempt {}): {}",
+                            self.label,
+                            split_host(&self.urls[index]).1,
+                            attempt,
+                            err
+                        ),
+                    );
+                    if attempt >= VALIDATION_MAX_RETRIES {
+                        return Err(err);
+                    }
+                    tokio::time::sleep(Duration::from_millis(100 << attempt)).await;
+                }
+            }
+        }
+    }
+
+    async fn connect(&mut self, owner: u32) -> Result<DatabasePool, sqlx::Error> {
+        let mut last_err = sqlx::Error::Configuration("No database host".into());
+        for index in 0..self.urls.len() {
+            match self.connect_host(index, owner).await {
+                Ok(pool) => {
+                    self.active = index;
+                    return Ok(pool);
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn check<T>(
+        &mut self,
+        pool: &mut DatabasePool,
+        res: &Result<T, sqlx::Error>,
+        owner: u32,
+        stats: &ConnectionStats,
+    ) {
+        let Err(err) = res else {
+            return;
+        };
+        if self.urls.len() < 2 || !is_connection_error(err) {
+            return;
+        }
+
+        for step in 1..self.urls.len() {
+            let next = (self.active + step) % self.urls.len();
+            if let Ok(new_pool) = self.connect_host(next, owner).await {
+                moon_log(
+                    owner,
+                    LOG_LEVEL_INFO,
+                    format!(
+                        "Database '{}' failover from '{}' to '{}'",
+                        self.label,
+                        self.active_host(),
+                        split_host(&self.urls[next]).1
+                    ),
+                );
+                *pool = new_pool;
+                self.active = next;
+                *stats.active_host.lock().unwrap() = self.active_host().to_string();
+                ServerVersion::refresh(pool, stats, owner, &self.label).await;
+                return;
+            }
+        }
+    }
+}
+
+const TRACE_SQL_MAX_LEN: usize = 1024;
+
+/// Binds are redacted to their kinds
+fn trace_query(owner: u32, label: &str, query: &DatabaseQuery) {
+    let mut end = query.sql.len().min(TRACE_SQL_MAX_LEN);
+    while !query.sql.is_char_boundary(end) {
+        end -= 1;
+    }
+    let ellipsis = if end < query.sql.len() { "..." } else { "" };
+    let binds = query
+        .binds
+        .iter()
+        .map(QueryParams::kind)
+        .collect::<Vec<_>>()
+        .join(", ");
+    moon_log(
+        owner,
+        LOG_LEVEL_DEBUG,
+        format!(
+            "Database '{}' trace: {}{} [{}]",
+            label,
+            &query.sql[..end],
+            ellipsis,
+            binds
+        ),
+    );
+}
+
+fn trace_request(label: &str, op: &DatabaseRequest) {
+    match op {
+        DatabaseRequest::Query(owner, _, query) => trace_query(*owner, label, query),
+        DatabaseRequest::Transaction(owner, _, querys, ..) => {
+            for query in querys {
+                trace_query(*owner, label, query);
+            }
+        }
+        DatabaseRequest::Export(owner, _, export) => trace_query(*owner, label, &export.query),
+        _ => {}
+    }
+}
+
+fn drain_requests(
+    rx: &mut mpsc::Receiver<DatabaseRequest>,
+    pending: &mut VecDeque<DatabaseRequest>,
+    conn: &DatabaseConnection,
+) {
+    while let Ok(op) = rx.try_recv() {
+        match op {
+            DatabaseRequest::CancelOwner(owner) => cancel_owner_requests(pending, conn, owner),
+            op => pending.push_back(op),
+        }
+    }
+}
+
+/// Also matches inside subqueries and strings, it only decides whether `max_rows` warns
+fn has_order_by(sql: &str) -> bool {
+    let mut words = sql.split_ascii_whitespace();
+    while let Some(word) = words.next() {
+        if word.trim_start_matches('(').eq_ignore_ascii_case("ORDER")
+            && words
+                .next()
+                .is_some_and(|next| next.eq_ignore_ascii_case("BY"))
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// After an optional leading `/* tag */` comment
+fn starts_with_keyword(sql: &str, keyword: &str) -> bool {
+    let mut sql = sql.trim_start();
+    if let Some(rest) = sql.strip_prefix("/*")
+        && let Some(end) = rest.find("*/")
+    {
+        sql = rest[end + 2..].trim_start();
+    }
+    sql.get(..keyword.len())
+        .is_some_and(|word| word.eq_ignore_ascii_case(keyword))
+        && !sql[keyword.len()..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is_read_query(sql: &str) -> bool {
+    starts_with_keyword(sql, "SELECT")
+}
+
+fn is_call(sql: &str) -> bool {
+    starts_with_keyword(sql, "CALL")
+}
+
+const VOLATILE_FUNCTIONS: &[&str] = &[
+    "random",
+    "rand",
+    "randomblob",
+    "nextval",
+    "setval",
+    "currval",
+    "lastval",
+    "uuid",
+    "uuid_short",
+    "gen_random_uuid",
+    "uuid_generate_v4",
+    "clock_timestamp",
+    "timeofday",
+    "sysdate",
+    "txid_current",
+    "last_insert_id",
+    "last_insert_rowid",
+    "found_rows",
+    "row_count",
+    "changes",
+    "get_lock",
+    "release_lock",
+    "sleep",
+    "pg_sleep",
+];
+
+/// Words match inside strings too, a false match only costs a shared result
+fn is_shareable_read(sql: &str) -> bool {
+    if !is_read_query(sql) {
+        return false;
+    }
+    let sql = sql.to_ascii_lowercase();
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut rest = sql.as_str();
+    let mut prev = "";
+    while let Some(begin) = rest.find(is_word) {
+        rest = &rest[begin..];
+        let end = rest.find(|c: char| !is_word(c)).unwrap_or(rest.len());
+        let word = &rest[..end];
+        rest = &rest[end..];
+        let call = rest.trim_start().starts_with('(');
+        if (prev == "for" && matches!(word, "update" | "share" | "no" | "key"))
+            || (prev == "lock" && word == "in")
+            || word == "into"
+            || (call && (VOLATILE_FUNCTIONS.contains(&word) || word.starts_with("pg_advisory")))
+        {
+            return false;
+        }
+        prev = word;
+    }
+    true
+}
+
+/// Only duplicates ahead of the first request that isn't a shareable read are answered
+fn share_result(
+    rx: &mut mpsc::Receiver<DatabaseRequest>,
+    pending: &mut VecDeque<DatabaseRequest>,
+    conn: &DatabaseConnection,
+    protocol_type: u8,
+    query: &DatabaseQuery,
+    response: &DatabaseResponse,
+) {
+    drain_requests(rx, pending, conn);
+    let mut reads = true;
+    pending.retain(|op| match op {
+        _ if !reads => true,
+        DatabaseRequest::Query(owner, session, other) if is_shareable_read(&other.sql) => {
+            if *session == 0 || other != query {
+                return true;
+            }
+            match response.share() {
+                Some(shared) => {
+                    conn.stats.send(protocol_type, *owner, *session, shared);
+                    conn.counter
+                        .fetch_sub(1, std::sync::atomic::Ordering::Release);
+                    false
+                }
+                None => true,
+            }
+        }
+        _ => {
+            reads = false;
+            true
+        }
+    });
+}
+
+fn cancel_owner_requests(
+    pending: &mut VecDeque<DatabaseRequest>,
+    conn: &DatabaseConnection,
+    owner: u32,
+) {
+    conn.cancels
+        .fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
+    pending.retain(|op| {
+        if op.owner() == Some(owner) {
+            conn.counter
+                .fetch_sub(1, std::sync::atomic::Ordering::Release);
+            false
+        } else {
+            true
+        }
+    });
+}
+
+async fn database_handler(
+    protocol_type: u8,
+    mut pool: DatabasePool,
+    mut rx: mpsc::Receiver<DatabaseRequest>,
+    mut failover: Failover,
+    conn: DatabaseConnection,
+    mut rate_limiter: Option<RateLimiter>,
+    decode: Arc<DecodeOptions>,
+) {
+    let counter = &conn.counter;
+    let stats = &conn.stats;
+    let mut pending = VecDeque::new();
+    let mut saturated = false;
+    let mut shutdown = db_shutdown_receiver();
+    let mut closing = false;
+    let mut drained = false;
+    let mut retry_due = HashMap::new();
+    loop {
+        // A cancel is in flight: drain the channel so the marker is found before
+        // any of the requests it cancels get executed.
+        if conn.cancels.load(std::sync::atomic::Ordering::Acquire) > 0 {
+            drain_requests(&mut rx, &mut pending, &conn);
+        }
+
+        let op = match next_request(&mut pending, &mut retry_due) {
+            Some(op) => op,
+            None => {
+                let due = retry_due.values().min().copied();
+                tokio::select! {
+                    op = rx.recv(), if !drained => {
+                        match op {
+                            Some(op) => pending.push_back(op),
+                            None if pending.is_empty() => break,
+                            None => drained = true,
+                        }
+                        continue;
+                    }
+                    // a deadlocked transaction is due to run again
+                    _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now).into()), if due.is_some() => continue,
+                    // stop accepting requests, the ones already queued still run before the pool closes
+                    _ = shutdown.wait_for(|stop| *stop), if !closing => {
+                        rx.close();
+                        closing = true;
+                        continue;
+                    }
+                    else => break,
+                }
+            }
+        };
+
+        let in_flight = counter.load(std::sync::atomic::Ordering::Acquire);
+        stats
+            .peak_pending
+            .fetch_max(in_flight, std::sync::atomic::Ordering::AcqRel);
+        if let Some(threshold) = failover.options.saturation_warn {
+            let ratio = saturation(in_flight, &conn.info);
+            if !saturated && ratio >= threshold {
+                moon_log(
+                    op.owner().unwrap_or_default(),
+                    LOG_LEVEL_WARN,
+                    format!(
+                        "Database '{}' pool saturation {:.2} reached {:.2}: {} requests in flight for {} connections",
+                        failover.label, ratio, threshold, in_flight, conn.info.max_connections
+                    ),
+                );
+            }
+            saturated = ratio >= threshold;
+        }
+
+        let mut failed_times = 0;
+        if let Some(limiter) = rate_limiter.as_mut()
+            && !matches!(
+                op,
+                DatabaseRequest::Close() | DatabaseRequest::CancelOwner(_)
+            )
+        {
+            let delay = limiter.acquire().await;
+            stats.throttle_delay_ms.store(
+                delay.as_millis() as u64,
+                std::sync::atomic::Ordering::Release,
+            );
+            if !delay.is_zero() {
+                stats
+                    .throttled
+                    .fetch_add(1, std::sync::atomic::Ordering::Release);
+            }
+        }
+        if stats.trace.load(std::sync::atomic::Ordering::Relaxed) {
+            trace_request(&failover.label, &op);
+        }
+        match &op {
+            DatabaseRequest::Query(owner, session, query_op) => loop {
+                let wrap = failover.options.wrap_in_transaction && !is_read_query(&query_op.sql);
+                let run = || async {
+                    if wrap {
+                        pool.query_in_transaction(query_op, &decode, &stats.acquire_waiters)
+                            .await
+                    } else {
+                        pool.query(query_op, &decode, &stats.acquire_waiters).await
+                    }
+                };
+                let mut res = match conn
+                    .composite_types
+                    .check(&pool, std::slice::from_ref(query_op))
+                    .await
+                {
+                    Ok(()) => run().await,
+                    Err(err) => Err(err),
+                };
+                if query_op.options.idempotent && res.as_ref().is_err_and(is_connection_reset) {
+                    res = run().await;
+                }
+                stats.record(&res);
+                failover.check(&mut pool, &res, *owner, stats).await;
+                if let Ok(response) = &res
+                    && failover.options.singleflight
+                    && is_shareable_read(&query_op.sql)
+                {
+                    share_result(
+                        &mut rx,
+                        &mut pending,
+                        &conn,
+                        protocol_type,
+                        query_op,
+                        response,
+                    );
+                }
+                if !handle_result(
+                    failover.active_host(),
+                    &mut failed_times,
+                    &conn,
+                    protocol_type,
+                    *owner,
+                    *session,
+                    res,
+                )
+                .await
+                {
+                    break;
+                }
+            },
+            DatabaseRequest::Transaction(owner, session, query_ops, attempt, attempts) => loop {
+                let slow = failover
+                    .options
+                    .slow_transaction_ms
+                    .map(|ms| SlowTransaction {
+                        threshold: Duration::from_millis(ms),
+                        owner: *owner,
+                        label: &failover.label,
+                    });
+                let res = match conn.composite_types.check(&pool, query_ops).await {
+                    Ok(()) => {
+                        pool.transaction(query_ops, &decode, &stats.acquire_waiters, slow.as_ref())
+                            .await
+                    }
+                    Err(err) => Err(err),
+                };
+                stats.record(&res);
+                failover.check(&mut pool, &res, *owner, stats).await;
+                match res {
+                    Err(err) if attempt < attempts && is_deadlock(&err) => {
+                        moon_log(
+                            *owner,
+                            LOG_LEVEL_WARN,
+                            format!(
+                                "Database '{}' transaction failed: {}. Retry {}/{}",
+                                failover.label,
+                                err,
+                                attempt,
+                                attempts - 1
+                            ),
+                        );
+                        retry_due.insert(*owner, Instant::now() + deadlock_backoff(*attempt));
+                        pending.push_front(DatabaseRequest::Transaction(
+                            *owner,
+                            *session,
+                            query_ops.clone(),
+                            attempt + 1,
+                            *attempts,
+                        ));
+                        break;
+                    }
+                    res => {
+                        if !handle_result(
+                            failover.active_host(),
+                            &mut failed_times,
+                            &conn,
+                            protocol_type,
+                            *owner,
+                            *session,
+                            res,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                    }
+                }
+            },
+            DatabaseRequest::Begin(owner, session) => loop {
+                let res = pool.begin(&stats.acquire_waiters).await.map(|transaction| {
+                    let (tx, rx) = mpsc::channel(16);
+                    tokio::spawn(session_handler(
+                        protocol_type,
+                        transaction,
+                        rx,
+                        decode.clone(),
+                        stats.clone(),
+                        failover.label.clone(),
+                        (pool.clone(), conn.composite_types.clone()),
+                    ));
+                    DatabaseResponse::Session(TransactionSession {
+                        tx,
+                        raise_errors: conn.raise_errors,
+                        no_json_autodetect: conn.no_json_autodetect,
+                        backslash_escapes: conn.info.backend == "mysql",
+                    })
+                });
+                stats.record(&res);
+                failover.check(&mut pool, &res, *owner, stats).await;
+                if !handle_result(
+                    failover.active_host(),
+                    &mut failed_times,
+                    &conn,
+                    protocol_type,
+                    *owner,
+                    *session,
+                    res,
+                )
+                .await
+                {
+                    break;
+                }
+            },
+            DatabaseRequest::Describe(owner, session, sql) => loop {
+                let res = pool.describe(sql).await;
+                stats.record(&res);
+                failover.check(&mut pool, &res, *owner, stats).await;
+                if !handle_result(
+                    failover.active_host(),
+                    &mut failed_times,
+                    &conn,
+                    protocol_type,
+                    *owner,
+                    *session,
+                    res,
+                )
+                .await
+                {
+                    break;
+                }
+            },
+            DatabaseRequest::EstimateCount(owner, session, table) => loop {
+                let res = pool.estimate_count(table).await;
+                stats.record(&res);
+                failover.check(&mut pool, &res, *owner, stats).await;
+                if !handle_result(
+                    failover.active_host(),
+                    &mut failed_times,
+                    &conn,
+                    protocol_type,
+                    *owner,
+                    *session,
+                    res,
+                )
+                .await
+                {
+                    break;
+                }
+            },
+            DatabaseRequest::Export(owner, session, export) => loop {
+                let res = match conn
+                    .composite_types
+                    .check(&pool, std::slice::from_ref(&export.query))
+                    .await
+                {
+                    Ok(()) => pool.export(export, &decode, &stats.acquire_waiters).await,
+                    Err(err) => Err(err),
+                };
+                stats.record(&res);
+                failover.check(&mut pool, &res, *owner, stats).await;
+                if !handle_result(
+                    failover.active_host(),
+                    &mut failed_times,
+                    &conn,
+                    protocol_type,
+                    *owner,
+                    *session,
+                    res,
+                )
+                .await
+                {
+                    break;
+                }
+            },
+            DatabaseRequest::Recycle(owner, session) => {
+                let closed = pool.recycle().await;
+                moon_log(
+                    *owner,
+                    LOG_LEVEL_INFO,
+                    format!(
+                        "Database '{}' recycled {} idle connections",
+                        failover.label, closed
+                    ),
+                );
+                handle_result(
+                    failover.active_host(),
+                    &mut failed_times,
+                    &conn,
+                    protocol_type,
+                    *owner,
+                    *session,
+                    Ok(DatabaseResponse::Recycled(closed)),
+                )
+                .await;
+            }
+            DatabaseRequest::Close() => {
+                break;
+            }
+            DatabaseRequest::CancelOwner(owner) => {
+                cancel_owner_requests(&mut pending, &conn, *owner);
+            }
+        }
+    }
+    pool.close().await;
+}
+
+/// The url is left out of the message, it may carry a password
+fn validate_url(database_url: &str) -> Result<(), String> {
+    let res = if database_url.starts_with("mysql://") {
+        MySqlConnectOptions::from_str(database_url).map(drop)
+    } else if database_url.starts_with("postgres://") {
+        PgConnectOptions::from_str(database_url).map(drop)
+    } else if database_url.starts_with("sqlite://") {
+        SqliteConnectOptions::from_str(database_url).map(drop)
+    } else {
+        return Err(
+            "unsupported database url, expected a mysql://, postgres:// or sqlite:// prefix"
+                .to_string(),
+        );
+    };
+    res.map_err(|err| format!("invalid database url: {}", err))
+}
+
+/// Errors name the variable, never the text around it, which may hold other secrets
+fn expand_env(text: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err("unterminated '${' in connection string".to_string());
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        match std::env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) => return Err(format!("environment variable '{}' is not set", name)),
+        }
+        rest = &rest[start + 2 + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Wraps `name` in `quote`, doubling any `quote` inside it
+fn quote_identifier(name: &str, quote: char) -> String {
+    let escaped = name.replace(quote, &format!("{}{}", quote, quote));
+    format!("{}{}{}", quote, escaped, quote)
+}
+
+fn schema_statement(database_url: &str, schema: &str) -> Result<String, String> {
+    let names: Vec<&str> = schema.split(',').map(str::trim).collect();
+    if names.iter().any(|name| name.is_empty()) {
+        return Err(format!("invalid schema '{}': empty name", schema));
+    }
+    if database_url.starts_with("postgres://") {
+        let names: Vec<String> = names
+            .iter()
+            .map(|name| quote_identifier(name, '"'))
+            .collect();
+        Ok(format!("SET search_path TO {}", names.join(", ")))
+    } else if database_url.starts_with("mysql://") {
+        match names.as_slice() {
+            [name] => Ok(format!("USE {}", quote_identifier(name, '`'))),
+            _ => Err(format!(
+                "invalid schema '{}': MySQL takes a single database",
+                schema
+            )),
+        }
+    } else {
+        Err("schema is only supported on MySQL and PostgreSQL".to_string())
+    }
+}
+
+extern "C-unwind" fn connect(state: LuaState) -> i32 {
+    let protocol_type: u8 = laux::lua_get(state, 1);
+    let owner = laux::lua_get(state, 2);
+    let session: i64 = laux::lua_get(state, 3);
+
+    let database_url: &str = laux::lua_get(state, 4);
+    let name: &str = laux::lua_get(state, 5);
+    let connect_timeout: u64 = laux::lua_opt(state, 6).unwrap_or(5000);
+    // its handler would stop at once, leaving a registered connection that fails every request
+    if db_shutting_down() {
+        return push_request_error(state, false, "connect refused: databases are shutting down");
+    }
+    let mut options = match ConnectOptions::from_lua(state, 7) {
+        Ok(options) => options,
+        Err(err) => return push_request_error(state, false, err),
+    };
+    let expanded = expand_env(database_url).and_then(|url| {
+        for host in options.hosts.iter_mut() {
+            *host = expand_env(host)?;
+        }
+        Ok(url)
+    });
+    let database_url = match expanded {
+        Ok(url) => url,
+        Err(err) => return push_request_error(state, false, err),
+    };
+
+    if let Some(schema) = options.schema.take() {
+        match schema_statement(&database_url, &schema) {
+            // first, so session_setup statements resolve names in the schema
+            Ok(sql) => options.session_setup.insert(0, sql),
+            Err(err) => return push_request_error(state, false, err),
+        }
+    }
+
+    let mut failover = Failover::new(
+        &database_url,
+        Duration::from_millis(connect_timeout),
+        options,
+        name,
+    );
+    if let Err(err) = failover.urls.iter().try_for_each(|url| validate_url(url)) {
+        return push_request_error(state, false, err);
+    }
+
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    PENDING_CONNECTS.insert(name.to_string(), cancel_tx);
+
+    CONTEXT.tokio_runtime.spawn(async move {
+        // a cancel drops the connect future, closing any pool it had opened so far. A sender
+        // dropped because a newer connect replaced it is not a cancel.
+        let res = tokio::select! {
+            res = failover.connect(owner) => res,
+            Ok(()) = cancel_rx => Err(sqlx::Error::Configuration(
+                format!("connect to '{}' cancelled", name).into(),
+            )),
+        };
+        // only this attempt's entry, a newer connect under the same name keeps its own
+        PENDING_CONNECTS.remove_if(name, |_, cancel_tx| cancel_tx.is_closed());
+        match res {
+            Ok(pool) => {
+                let (tx, rx) = mpsc::channel(100);
+                let conn = DatabaseConnection {
+                    tx,
+                    counter: Arc::new(AtomicI64::new(0)),
+                    stats: Arc::new(ConnectionStats::default()),
+                    cancels: Arc::new(AtomicUsize::new(0)),
+                    raise_errors: failover.options.raise_errors,
+                    numbered_params: matches!(pool, DatabasePool::Postgres(_)),
+                    no_json_autodetect: failover.options.no_json_autodetect,
+                    max_inflight: failover.options.max_inflight,
+                    max_sql_bytes: failover.options.max_sql_bytes,
+                    composite_types: CompositeTypes::default(),
+                    info: Arc::new(ConnectionInfo::new(&pool, &failover)),
+                };
+                *conn.stats.active_host.lock().unwrap() = failover.active_host().to_string();
+                ServerVersion::refresh(&pool, &conn.stats, owner, &failover.label).await;
+                DATABASE_CONNECTIONSS.insert(name.to_string(), conn.clone());
+                moon_send(protocol_type, owner, session, DatabaseResponse::Connect);
+                let rate_limiter = failover.options.rate_limit_qps.map(RateLimiter::new);
+                let decode = Arc::new(failover.options.decode.clone());
+                database_handler(
+                    protocol_type,
+                    pool,
+                    rx,
+                    failover,
+                    conn,
+                    rate_limiter,
+                    decode,
+                )
+                .await;
+            }
+            Err(err) => {
+                moon_send(
+                    protocol_type,
+                    owner,
+                    session,
+                    DatabaseResponse::Timeout(err.to_string()),
+                );
+            }
+        };
+    });
+
+    push_accepted(state, session)
+}
+
+/// Strings that parse as JSON objects/arrays bind as JSON unless `json_autodetect` is off
+fn get_query_param(state: LuaState, i: i32, json_autodetect: bool) -> Result<QueryParams, String> {
+    let options = JsonOptions::default();
+
+    let res = match LuaValue::from_stack(state, i) {
+        LuaValue::Boolean(val) => QueryParams::Bool(val),
+        LuaValue::Number(val) => QueryParams::Float(val),
+        LuaValue::Integer(val) => QueryParams::Int(val),
+        LuaValue::String(val) => {
+            if json_autodetect && (val.starts_with(b"{") || val.starts_with(b"[")) {
+                if let Ok(value) = serde_json::from_slice::<serde_json::Value>(val) {
+                    QueryParams::Json(value)
+                } else {
+                    QueryParams::Text(unsafe { String::from_utf8_unchecked(val.to_vec()) })
+                }
+            } else {
+                QueryParams::Text(unsafe { String::from_utf8_unchecked(val.to_vec()) })
+            }
+        }
+        LuaValue::Table(val) => {
+            let mut buffer = Vec::new();
+            if let Err(err) = encode_table(&mut buffer, &val, 0, false, &options) {
+                drop(buffer);
+                laux::lua_error(state, err);
+            }
+            if buffer[0] == b'{' || buffer[0] == b'[' {
+                if let Ok(value) = serde_json::from_slice::<serde_json::Value>(buffer.as_slice()) {
+                    QueryParams::Json(value)
+                } else {
+                    QueryParams::Bytes(buffer)
+                }
+            } else {
+                QueryParams::Bytes(buffer)
+            }
+        }
+        LuaValue::UserData(_) => {
+            let param = unsafe { ffi::luaL_testudata(state.as_ptr(), i, PARAM_METATABLE) }
+                as *const QueryParams;
+            if param.is_null() {
+                return Err("get_query_param: unsupport userdata param".to_string());
+            }
+            unsafe { (*param).clone() }
+        }
+        _t => {
+            return Err(format!(
+                "get_query_param: unsupport value type :{}",
+                laux::type_name(state, i)
+            ));
+        }
+    };
+    Ok(res)
+}
+
+const PARAM_METATABLE: *const std::ffi::c_char = cstr!("sqlx_param_metatable");
+
+fn push_param(state: LuaState, param: QueryParams) -> i32 {
+    if laux::lua_newuserdata(state, param, PARAM_METATABLE, &[lreg_null!()]).is_none() {
+        laux::lua_pushnil(state);
+    }
+    1
+}
+
+fn json_arg(state: LuaState, index: i32) -> serde_json::Value {
+    let res = match LuaValue::from_stack(state, index) {
+        LuaValue::String(val) => serde_json::from_slice(val).map_err(|err| err.to_string()),
+        LuaValue::Table(val) => {
+            let mut buffer = Vec::new();
+            encode_table(&mut buffer, &val, 0, false, &JsonOptions::default()).and_then(|_| {
+                serde_json::from_slice(buffer.as_slice()).map_err(|err| err.to_string())
+            })
+        }
+        _ => Err("expects a json string or table".to_string()),
+    };
+
+    match res {
+        Ok(value) => value,
+        Err(err) => laux::lua_error(state, format!("invalid json param: {}", err)),
+    }
+}
+
+extern "C-unwind" fn bind_json(state: LuaState) -> i32 {
+    let value = json_arg(state, 1);
+    push_param(state, QueryParams::JsonText(value))
+}
+
+extern "C-unwind" fn bind_jsonb(state: LuaState) -> i32 {
+    let value = json_arg(state, 1);
+    push_param(state, QueryParams::Jsonb(value))
+}
+
+extern "C-unwind" fn bind_bytea_array(state: LuaState) -> i32 {
+    laux::lua_checktype(state, 1, ffi::LUA_TTABLE);
+    let mut values = Vec::new();
+    for value in LuaTable::from_stack(state, 1).array_iter() {
+        match value {
+            LuaValue::String(bytes) => values.push(bytes.to_vec()),
+            _ => {
+                let position = values.len() + 1;
+                drop(values);
+                laux::lua_error(
+                    state,
+                    format!("bytea_array expects byte strings, item {} is not", position),
+                );
+            }
+        }
+    }
+    push_param(state, QueryParams::BytesArray(values))
+}
+
+/// NULL elements are given as `json.null`, a nil would end the array
+extern "C-unwind" fn bind_bool_array(state: LuaState) -> i32 {
+    laux::lua_checktype(state, 1, ffi::LUA_TTABLE);
+    let mut values = Vec::new();
+    for value in LuaTable::from_stack(state, 1).array_iter() {
+        match value {
+            LuaValue::Boolean(value) => values.push(Some(value)),
+            LuaValue::LightUserData(_) => values.push(None),
+            _ => {
+                let position = values.len() + 1;
+                drop(values);
+                laux::lua_error(
+                    state,
+                    format!(
+                        "bool_array expects booleans or json.null, item {} is not",
+                        position
+                    ),
+                );
+            }
+        }
+    }
+    push_param(state, QueryParams::BoolArray(values))
+}
+
+extern "C-unwind" fn bind_time(state: LuaState) -> i32 {
+    let value: &str = laux::lua_get(state, 1);
+    match NaiveTime::parse_from_str(value, "%H:%M:%S%.f") {
+        Ok(time) => push_param(state, QueryParams::Time(time)),
+        Err(err) => laux::lua_error(state, format!("invalid time '{}': {}", value, err)),
+    }
+}
+
+extern "C-unwind" fn bind_tsquery(state: LuaState) -> i32 {
+    let text: &str = laux::lua_get(state, 1);
+    push_param(state, QueryParams::TsQuery(text.to_string()))
+}
+
+extern "C-unwind" fn bind_maybe(state: LuaState) -> i32 {
+    match LuaValue::from_stack(state, 1) {
+        LuaValue::None | LuaValue::Nil => push_param(state, QueryParams::Null),
+        LuaValue::LightUserData(p) if p.is_null() => push_param(state, QueryParams::Null),
+        _ => {
+            unsafe { ffi::lua_pushvalue(state.as_ptr(), 1) };
+            1
+        }
+    }
+}
+
+extern "C-unwind" fn bind_decimal(state: LuaState) -> i32 {
+    let scale: Option<u32> = laux::lua_opt(state, 2);
+    let res = match LuaValue::from_stack(state, 1) {
+        LuaValue::Integer(value) => Decimal::try_new(value, scale.unwrap_or(0))
+            .map_err(|err| format!("decimal {} scale {}: {}", value, scale.unwrap_or(0), err)),
+        LuaValue::String(text) => std::str::from_utf8(text)
+            .map_err(|err| err.to_string())
+            .and_then(|text| {
+                Decimal::from_str_exact(text.trim())
+                    .map_err(|err| format!("invalid decimal '{}': {}", text, err))
+            })
+            .and_then(|mut value| match scale {
+                Some(scale) if value.scale() > scale => Err(format!(
+                    "decimal '{}' has more than {} decimal places",
+                    value, scale
+                )),
+                Some(scale) => {
+                    value.rescale(scale);
+                    Ok(value)
+                }
+                None => Ok(value),
+            }),
+        _ => Err(format!(
+            "decimal expects a string or an integer, got {}",
+            laux::type_name(state, 1)
+        )),
+    };
+    match res {
+        Ok(value) => push_param(state, QueryParams::Decimal(value)),
+        Err(err) => laux::lua_error(state, err),
+    }
+}
+
+extern "C-unwind" fn bind_interval(state: LuaState) -> i32 {
+    let seconds: f64 = laux::lua_get(state, 1);
+    if !seconds.is_finite() {
+        laux::lua_error(
+            state,
+            "interval expects a finite number of seconds".to_string(),
+        );
+    }
+    push_param(state, QueryParams::Interval(seconds))
+}
+
+extern "C-unwind" fn bind_interval_array(state: LuaState) -> i32 {
+    laux::lua_checktype(state, 1, ffi::LUA_TTABLE);
+    let mut values = Vec::new();
+    for value in LuaTable::from_stack(state, 1).array_iter() {
+        match value {
+            LuaValue::Integer(seconds) => values.push(seconds as f64),
+            LuaValue::Number(seconds) if seconds.is_finite() => values.push(seconds),
+            _ => {
+                let position = values.len() + 1;
+                drop(values);
+                laux::lua_error(
+                    state,
+                    format!(
+                        "interval_array expects finite numbers of seconds, item {} is not",
+                        position
+                    ),
+                );
+            }
+        }
+    }
+    push_param(state, QueryParams::IntervalArray(values))
+}
+
+/// MySQL `UUID_TO_BIN(uuid, 1)` order
+fn uuid_swap(b: [u8; 16]) -> [u8; 16] {
+    [
+        b[6], b[7], b[4], b[5], b[0], b[1], b[2], b[3], b[8], b[9], b[10], b[11], b[12], b[13],
+        b[14], b[15],
+    ]
+}
+
+fn uuid_unswap(b: [u8; 16]) -> [u8; 16] {
+    [
+        b[4], b[5], b[6], b[7], b[2], b[3], b[0], b[1], b[8], b[9], b[10], b[11], b[12], b[13],
+        b[14], b[15],
+    ]
+}
+
+extern "C-unwind" fn bind_uuid_bin(state: LuaState) -> i32 {
+    let value: &str = laux::lua_get(state, 1);
+    let swap: bool = laux::lua_opt(state, 2).unwrap_or(false);
+
+    let bytes = match Uuid::parse_str(value) {
+        Ok(uuid) => *uuid.as_bytes(),
+        Err(err) => laux::lua_error(state, format!("invalid uuid '{}': {}", value, err)),
+    };
+    let bytes = if swap { uuid_swap(bytes) } else { bytes };
+    push_param(state, QueryParams::Bytes(bytes.to_vec()))
+}
+
+fn push_record_field(literal: &mut String, value: &str) {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| matches!(c, '"' | '\\' | '(' | ')' | ',') || c.is_whitespace());
+    if !needs_quotes {
+        literal.push_str(value);
+        return;
+    }
+    literal.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            literal.push(c);
+        }
+        literal.push(c);
+    }
+    literal.push('"');
+}
+
+extern "C-unwind" fn bind_record(state: LuaState) -> i32 {
+    laux::lua_checktype(state, 1, ffi::LUA_TTABLE);
+    let type_name: &str = laux::lua_get(state, 2);
+
+    let mut literal = String::from("(");
+    let mut fields = 0;
+    let mut error = None;
+    for value in LuaTable::from_stack(state, 1).array_iter() {
+        if fields > 0 {
+            literal.push(',');
+        }
+        fields += 1;
+        match value {
+            // NULL (json.null) leaves the field empty
+            LuaValue::LightUserData(_) => {}
+            LuaValue::Boolean(value) => literal.push(if value { 't' } else { 'f' }),
+            LuaValue::Integer(value) => literal.push_str(&value.to_string()),
+            LuaValue::Number(value) if value.is_nan() => literal.push_str("NaN"),
+            LuaValue::Number(value) if value.is_infinite() => {
+                literal.push_str(if value > 0.0 { "Infinity" } else { "-Infinity" })
+            }
+            LuaValue::Number(value) => literal.push_str(&value.to_string()),
+            LuaValue::String(value) => {
+                push_record_field(&mut literal, &String::from_utf8_lossy(value))
+            }
+            _ => {
+                error = Some(format!(
+                    "record field {} must be a boolean, number, string or null",
+                    fields
+                ));
+                break;
+            }
+        }
+    }
+    literal.push(')');
+
+    if let Some(error) = error {
+        drop(literal);
+        laux::lua_error(state, error);
+    }
+    push_param(
+        state,
+        QueryParams::Record {
+            literal,
+            type_name: type_name.to_string(),
+            fields,
+        },
+    )
+}
+
+extern "C-unwind" fn bind_char(state: LuaState) -> i32 {
+    let value: &str = laux::lua_get(state, 1);
+    let length: usize = laux::lua_get(state, 2);
+    let pad: bool = laux::lua_opt(state, 3).unwrap_or(true);
+
+    let value = value.trim_end_matches(' ');
+    let chars = value.chars().count();
+    if chars > length {
+        laux::lua_error(
+            state,
+            format!(
+                "char value has {} characters, longer than CHAR({})",
+                chars, length
+            ),
+        );
+    }
+    let text = if pad {
+        format!("{:<width$}", value, width = length)
+    } else {
+        value.to_string()
+    };
+    push_param(state, QueryParams::Text(text))
+}
+
+#[derive(Default)]
+struct QueryOptions {
+    /// bind position (1-based) -> declared column type, to range-check integer params
+    param_types: Vec<(usize, String, DbType)>,
+    tag: Option<String>,
+    no_json_autodetect: bool,
+    backslash_escapes: bool,
+    rows: RowsOptions,
+}
+
+impl QueryOptions {
+    fn from_lua(state: LuaState, index: i32) -> Result<Self, String> {
+        let mut options = Self::default();
+        if laux::lua_type(state, index) != LuaType::Table {
+            return Ok(options);
+        }
+
+        options.rows.stream = laux::opt_field(state, index, "stream").unwrap_or(false);
+        // without '*' the tag can't close the comment early
+        options.tag = laux::opt_field::<String>(state, index, "tag")
+            .map(|tag| tag.replace(['*', '\0'], ""))
+            .filter(|tag| !tag.is_empty());
+        options.rows.with_timing = laux::opt_field(state, index, "with_timing").unwrap_or(false);
+        options.no_json_autodetect =
+            laux::opt_field(state, index, "no_json_autodetect").unwrap_or(false);
+        options.rows.json_as_table =
+            laux::opt_field(state, index, "json_as_table").unwrap_or(false);
+        options.rows.empty_as_nil = laux::opt_field(state, index, "empty_as_nil");
+        options.rows.uuid_swap = laux::opt_field(state, index, "uuid_swap").unwrap_or(false);
+        options.rows.flat = laux::opt_field(state, index, "flat").unwrap_or(false);
+        options.rows.with_count = laux::opt_field(state, index, "with_count").unwrap_or(false);
+        options.rows.max_rows = laux::opt_field(state, index, "max_rows");
+        options.rows.idempotent = laux::opt_field(state, index, "idempotent").unwrap_or(false);
+        options.rows.result_sets = laux::opt_field(state, index, "result_sets").unwrap_or(false);
+        options.rows.geometry_srid =
+            laux::opt_field(state, index, "geometry_srid").unwrap_or(false);
+        options.rows.nulls = match (
+            laux::opt_field::<String>(state, index, "null_sentinel"),
+            laux::opt_field::<&str>(state, index, "nulls"),
+        ) {
+            (Some(sentinel), None | Some("sentinel")) => NullCells::Sentinel(sentinel),
+            (None, None | Some("omit")) => NullCells::Omit,
+            (None, Some("null")) => NullCells::JsonNull,
+            (None, Some("sentinel")) => {
+                return Err("nulls = 'sentinel' needs null_sentinel".to_string());
+            }
+            (_, Some(other)) => {
+                return Err(format!(
+                    "nulls expects 'omit', 'null' or 'sentinel', got '{}'",
+                    other
+                ));
+            }
+        };
+        options.rows.json_numbers = match laux::opt_field::<&str>(state, index, "json_numbers") {
+            None | Some("preserve") => JsonNumbers::Preserve,
+            Some("float") => JsonNumbers::Float,
+            Some(other) => {
+                return Err(format!(
+                    "json_numbers expects 'preserve' or 'float', got '{}'",
+                    other
+                ));
+            }
+        };
+
+        let table = LuaTable::from_stack(state, index);
+        options.rows.uuid_columns = string_array(&table, "uuid_columns", "column names")?;
+        let defaults = table.rawget("defaults");
+        match &defaults.value {
+            LuaValue::Table(defaults) => {
+                for (key, value) in defaults.iter() {
+                    let LuaValue::String(column) = key else {
+                        return Err("defaults expects {column = value, ...}".to_string());
+                    };
+                    let column = String::from_utf8_lossy(column).into_owned();
+                    // strings stay text, get_query_param would turn "{...}" into JSON
+                    let default = match value {
+                        LuaValue::String(text) => {
+                            QueryParams::Text(String::from_utf8_lossy(text).into_owned())
+                        }
+                        _ => get_query_param(state, -1, true)?,
+                    };
+                    options.rows.defaults.push((column, default));
+                }
+            }
+            LuaValue::Nil => {}
+            _ => return Err("defaults expects {column = value, ...}".to_string()),
+        }
+        let encodings = table.rawget("encodings");
+        match &encodings.value {
+            LuaValue::Table(encodings) => {
+                for (key, value) in encodings.iter() {
+                    let (LuaValue::String(column), LuaValue::String(label)) = (key, value) else {
+                        return Err("encodings expects {column = 'encoding', ...}".to_string());
+                    };
+                    let column = String::from_utf8_lossy(column).into_owned();
+                    let Some(encoding) = Encoding::for_label(label) else {
+                        return Err(format!(
+                            "encodings: unknown encoding '{}' for column '{}'",
+                            String::from_utf8_lossy(label),
+                            column
+                        ));
+                    };
+                    options.rows.encodings.push((column, encoding));
+                }
+            }
+            LuaValue::Nil => {}
+            _ => return Err("encodings expects {column = 'encoding', ...}".to_string()),
+        }
+        let columns = table.rawget("columns");
+        match &columns.value {
+            LuaValue::Table(names) => {
+                let mut list = Vec::with_capacity(names.len());
+                for name in names.array_iter() {
+                    match name {
+                        LuaValue::String(name) => {
+                            list.push(String::from_utf8_lossy(name).into_owned())
+                        }
+                        _ => return Err("columns expects an array of column names".to_string()),
+                    }
+                }
+                options.rows.columns = Some(list);
+            }
+            LuaValue::Nil => {}
+            _ => return Err("columns expects an array of column names".to_string()),
+        }
+
+        let param_types = table.rawget("param_types");
+        if let LuaValue::Table(types) = &param_types.value {
+            for (key, value) in types.iter() {
+                match (key, value) {
+                    (LuaValue::Integer(position), LuaValue::String(name)) if position > 0 => {
+                        let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+                        let db_type = DbType::from_name(&name);
+                        options.param_types.push((position as usize, name, db_type));
+                    }
+                    _ => {
+                        return Err(
+                            "param_types expects {[position] = 'COLUMN TYPE', ...}".to_string()
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn check_param(&self, position: usize, param: QueryParams) -> Result<QueryParams, String> {
+        match self.param_types.iter().find(|(pos, _, _)| *pos == position) {
+            Some((_, name, db_type)) => coerce_integer_param(position, name, *db_type, param),
+            None => Ok(param),
+        }
+    }
+}
+
+fn coerce_integer_param(
+    position: usize,
+    type_name: &str,
+    db_type: DbType,
+    param: QueryParams,
+) -> Result<QueryParams, String> {
+    let (min, max) = match db_type {
+        DbType::Int8 => (i8::MIN as i64, i8::MAX as i64),
+        DbType::UInt8 => (0, u8::MAX as i64),
+        DbType::Int16 => (i16::MIN as i64, i16::MAX as i64),
+        DbType::UInt16 => (0, u16::MAX as i64),
+        DbType::Int32 => (i32::MIN as i64, i32::MAX as i64),
+        DbType::UInt32 => (0, u32::MAX as i64),
+        DbType::Int64 => (i64::MIN, i64::MAX),
+        DbType::UInt64 => (0, i64::MAX),
+        _ => return Ok(param),
+    };
+
+    let value = match param {
+        QueryParams::Int(value) => value,
+        QueryParams::Float(value)
+            if value.fract() == 0.0 && value >= i64::MIN as f64 && value <= i64::MAX as f64 =>
+        {
+            value as i64
+        }
+        QueryParams::Bool(_) | QueryParams::Null => return Ok(param),
+        _ => {
+            return Err(format!(
+                "param #{} expects an integer for {} column",
+                position, type_name
+            ));
+        }
+    };
+
+    if value < min || value > max {
+        return Err(format!(
+            "param #{} value {} out of range for {} column [{}, {}]",
+            position, value, type_name, min, max
+        ));
+    }
+
+    Ok(QueryParams::Int(value))
+}
+
+/// Fire-and-forget (session 0) rejections are returned, never raised, so the caller sees `false`
+fn raises(conn: &DatabaseConnection, session: i64) -> bool {
+    conn.raise_errors && session != 0
+}
+
+/// `session, true`, so session 0 calls can tell acceptance from a rejection table
+fn push_accepted(state: LuaState, session: i64) -> i32 {
+    laux::lua_push(state, session);
+    laux::lua_push(state, true);
+    2
+}
+
+/// Takes `err` by value so it's dropped before `lua_error` unwinds past this frame
+fn push_request_error(state: LuaState, raise_errors: bool, err: impl ToString) -> i32 {
+    push_request_error_kind(state, raise_errors, "ERROR", err)
+}
+
+fn push_request_error_kind(
+    state: LuaState,
+    raise_errors: bool,
+    kind: &str,
+    err: impl ToString,
+) -> i32 {
+    let message = err.to_string();
+    drop(err);
+    if raise_errors {
+        laux::lua_error(state, message);
+    }
+    push_lua_table!(
+        state,
+        "kind" => kind,
+        "message" => message
+    );
+    1
+}
+
+fn check_sql_size(conn: &DatabaseConnection, sql: &str) -> Result<(), String> {
+    match conn.max_sql_bytes {
+        Some(max_sql_bytes) if sql.len() > max_sql_bytes => Err(format!(
+            "sql too large: {} bytes, max_sql_bytes is {}",
+            sql.len(),
+            max_sql_bytes
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_inflight(conn: &DatabaseConnection) -> Result<(), String> {
+    let Some(max_inflight) = conn.max_inflight else {
+        return Ok(());
+    };
+    let inflight = conn.counter.load(std::sync::atomic::Ordering::Acquire);
+    if inflight >= max_inflight {
+        return Err(format!(
+            "overloaded: {} requests in flight, max_inflight is {}",
+            inflight, max_inflight
+        ));
+    }
+    Ok(())
+}
+
+/// Highest `$n`, or else the number of `?`, outside strings, `$tag$` bodies and comments.
+/// `backslash_escapes`: `\` escapes in every quoted string (MySQL), otherwise only in `E'...'`
+fn count_placeholders(sql: &str, backslash_escapes: bool) -> usize {
+    let bytes = sql.as_bytes();
+    let is_ident = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'$';
+    let mut questions = 0;
+    let mut numbered = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'\'' | b'"' | b'`') => {
+                let escapes = backslash_escapes
+                    || (quote == b'\''
+                        && i > 0
+                        && matches!(bytes[i - 1], b'E' | b'e')
+                        && (i < 2 || !is_ident(bytes[i - 2])));
+                i += 1;
+                while i < bytes.len() {
+                    if escapes && bytes[i] == b'\\' {
+                        i += 1;
+                    } else if bytes[i] == quote {
+                        // a doubled quote is an escaped one
+                        if bytes.get(i + 1) != Some(&quote) {
+                            break;
+                        }
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i < bytes.len() && !(bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/')) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'?' => questions += 1,
+            // `$$` or `$tag$` opens a dollar-quoted string, closed by the same tag
+            b'$' if (i == 0 || !is_ident(bytes[i - 1]))
+                && !bytes.get(i + 1).is_some_and(u8::is_ascii_digit) =>
+            {
+                let tag_len = bytes[i + 1..]
+                    .iter()
+                    .position(|&b| b == b'$')
+                    .filter(|&n| bytes[i + 1..i + 1 + n].iter().all(|&b| is_ident(b)));
+                if let Some(n) = tag_len {
+                    let tag = &sql[i..i + n + 2];
+                    i = match sql[i + tag.len()..].find(tag) {
+                        Some(end) => i + tag.len() + end + tag.len() - 1,
+                        None => bytes.len(),
+                    };
+                }
+            }
+            b'$' => {
+                let start = i + 1;
+                let mut end = start;
+                while end < bytes.len() && bytes[end].is_ascii_digit() {
+                    end += 1;
+                }
+                if let Ok(n) = sql[start..end].parse::<usize>() {
+                    numbered = numbered.max(n);
+                }
+                i = end - 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    if numbered > 0 { numbered } else { questions }
+}
+
+/// A lone array table is splatted when `sql` has as many placeholders, more than one
+fn stack_params(
+    state: LuaState,
+    sql: &str,
+    first: i32,
+    json_autodetect: bool,
+    backslash_escapes: bool,
+) -> Result<Vec<QueryParams>, String> {
+    let top = laux::lua_top(state);
+    if first == top && laux::lua_type(state, first) == LuaType::Table {
+        let (is_array, len) = LuaTable::from_stack(state, first).array_len();
+        if is_array && len > 1 && count_placeholders(sql, backslash_escapes) == len {
+            return array_to_params(state, first, json_autodetect);
+        }
+    }
+    (first..=top)
+        .map(|i| get_query_param(state, i, json_autodetect))
+        .collect()
+}
+
+fn read_query(
+    state: LuaState,
+    options: QueryOptions,
+    mut args: LuaArgs,
+) -> Result<DatabaseQuery, String> {
+    let sql = laux::lua_get::<&str>(state, args.iter_arg());
+    let params = stack_params(
+        state,
+        sql,
+        args.iter_arg(),
+        !options.no_json_autodetect,
+        options.backslash_escapes,
+    )?
+    .into_iter()
+    .enumerate()
+    .map(|(n, param)| options.check_param(n + 1, param))
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut rows_options = options.rows;
+    rows_options.unordered = rows_options.max_rows.is_some() && !has_order_by(sql);
+    let sql = match &options.tag {
+        Some(tag) => format!("/* {} */ {}", tag, sql),
+        None => sql.to_string(),
+    };
+
+    Ok(DatabaseQuery {
+        sql,
+        binds: params,
+        options: rows_options,
+    })
+}
+
+fn send_query(
+    state: LuaState,
+    conn: &DatabaseConnection,
+    owner: u32,
+    session: i64,
+    mut options: QueryOptions,
+    args: LuaArgs,
+) -> i32 {
+    let raise_errors = raises(conn, session);
+    options.no_json_autodetect |= conn.no_json_autodetect;
+    options.backslash_escapes = conn.info.backend == "mysql";
+    if let Err(err) = check_inflight(conn) {
+        return push_request_error_kind(state, raise_errors, "OVERLOADED", err);
+    }
+    let query = match read_query(state, options, args) {
+        Ok(query) => query,
+        Err(err) => return push_request_error(state, raise_errors, err),
+    };
+    if let Err(err) = check_sql_size(conn, &query.sql) {
+        drop(query);
+        return push_request_error_kind(state, raise_errors, "SQL_TOO_LARGE", err);
+    }
+    if query.options.result_sets && conn.info.backend != "mysql" {
+        drop(query);
+        return push_request_error(
+            state,
+            raise_errors,
+            "result_sets is only supported on MySQL",
+        );
+    }
+
+    match conn
+        .tx
+        .try_send(DatabaseRequest::Query(owner, session, query))
+    {
+        Ok(_) => {
+            conn.counter
+                .fetch_add(1, std::sync::atomic::Ordering::Release);
+            push_accepted(state, session)
+        }
+        Err(err) => push_request_error(state, raise_errors, err),
+    }
+}
+
+extern "C-unwind" fn query(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+
+    send_query(state, conn, owner, session, QueryOptions::default(), args)
+}
+
+extern "C-unwind" fn query_with(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+
+    let options = match QueryOptions::from_lua(state, args.iter_arg()) {
+        Ok(options) => options,
+        Err(err) => return push_request_error(state, raises(conn, session), err),
+    };
+
+    send_query(state, conn, owner, session, options, args)
+}
+
+const MAX_DEADLOCK_ATTEMPTS: u32 = 10;
+
+/// A send rejected by a full or closed queue puts the queries back, so it can be sent again
+struct TransactionQuerys {
+    querys: Vec<DatabaseQuery>,
+    json_autodetect: bool,
+    backslash_escapes: bool,
+    attempts: u32,
+}
+
+extern "C-unwind" fn push_transaction_query(state: LuaState) -> i32 {
+    let querys = laux::lua_touserdata::<TransactionQuerys>(state, 1)
+        .expect("Invalid transaction query pointer");
+
+    let sql = laux::lua_get::<&str>(state, 2);
+    let params = match stack_params(
+        state,
+        sql,
+        3,
+        querys.json_autodetect,
+        querys.backslash_escapes,
+    ) {
+        Ok(params) => params,
+        Err(err) => laux::lua_error(state, err),
+    };
+
+    querys.querys.push(DatabaseQuery {
+        sql: sql.to_string(),
+        binds: params,
+        options: RowsOptions::default(),
+    });
+
+    0
+}
+
+extern "C-unwind" fn label_transaction_query(state: LuaState) -> i32 {
+    let querys = laux::lua_touserdata::<TransactionQuerys>(state, 1)
+        .expect("Invalid transaction query pointer");
+    let label: &str = laux::lua_get(state, 2);
+
+    match querys.querys.last_mut() {
+        Some(query) => query.options.label = Some(label.to_string()),
+        None => laux::lua_error(state, "label: no statement pushed yet".to_string()),
+    }
+    0
+}
+
+extern "C-unwind" fn retry_transaction_on_deadlock(state: LuaState) -> i32 {
+    let querys = laux::lua_touserdata::<TransactionQuerys>(state, 1)
+        .expect("Invalid transaction query pointer");
+    let attempts: i64 = laux::lua_get(state, 2);
+
+    match deadlock_attempts(attempts) {
+        Ok(attempts) => querys.attempts = attempts,
+        Err(err) => laux::lua_error(state, err),
+    }
+    0
+}
+
+fn deadlock_attempts(attempts: i64) -> Result<u32, String> {
+    match u32::try_from(attempts) {
+        Ok(attempts @ 1..=MAX_DEADLOCK_ATTEMPTS) => Ok(attempts),
+        _ => Err(format!(
+            "retry_on_deadlock expects 1 to {} attempts, got {}",
+            MAX_DEADLOCK_ATTEMPTS, attempts
+        )),
+    }
+}
+
+extern "C-unwind" fn make_transaction(state: LuaState) -> i32 {
+    // the connection is optional, it only carries `no_json_autodetect` and the backend
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1);
+    let json_autodetect = conn.as_ref().is_none_or(|conn| !conn.no_json_autodetect);
+    let backslash_escapes = conn.is_some_and(|conn| conn.info.backend == "mysql");
+    laux::lua_newuserdata(
+        state,
+        TransactionQuerys {
+            querys: Vec::new(),
+            json_autodetect,
+            backslash_escapes,
+            attempts: 1,
+        },
+        cstr!("sqlx_transaction_metatable"),
+        &[
+            lreg!("push", push_transaction_query),
+            lreg!("label", label_transaction_query),
+            lreg!("retry_on_deadlock", retry_transaction_on_deadlock),
+            lreg_null!(),
+        ],
+    );
+    1
+}
+
+extern "C-unwind" fn transaction(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+
+    let querys = laux::lua_touserdata::<TransactionQuerys>(state, args.iter_arg())
+        .expect("Invalid transaction query pointer");
+
+    let raise_errors = raises(conn, session);
+    if let Err(err) = check_inflight(conn) {
+        return push_request_error_kind(state, raise_errors, "OVERLOADED", err);
+    }
+    match conn.tx.try_send(DatabaseRequest::Transaction(
+        owner,
+        session,
+        std::mem::take(&mut querys.querys),
+        1,
+        querys.attempts,
+    )) {
+        Ok(_) => {
+            conn.counter
+                .fetch_add(1, std::sync::atomic::Ordering::Release);
+            push_accepted(state, session)
+        }
+        Err(err) => {
+            let message = err.to_string();
+            if let DatabaseRequest::Transaction(_, _, taken, ..) = err.into_inner() {
+                querys.querys = taken;
+            }
+            push_request_error(state, raise_errors, message)
+        }
+    }
+}
+
+fn table_to_querys(
+    state: LuaState,
+    index: i32,
+    json_autodetect: bool,
+) -> Result<Vec<DatabaseQuery>, String> {
+    let table = LuaTable::from_stack(state, index);
+    let mut querys = Vec::with_capacity(table.len());
+    for (n, entry) in table.array_iter().enumerate() {
+        let LuaValue::Table(entry) = entry else {
+            return Err(format!(
+                "transaction entry #{} expects {{sql, {{params...}}}}",
+                n + 1
+            ));
+        };
+
+        let sql = match &entry.rawget(1).value {
+            LuaValue::String(sql) => String::from_utf8_lossy(sql).into_owned(),
+            _ => return Err(format!("transaction entry #{} missing sql string", n + 1)),
+        };
+
+        let mut binds = Vec::new();
+        let params = entry.rawget(2);
+        match &params.value {
+            LuaValue::Table(params) => {
+                for _ in params.array_iter() {
+                    binds.push(get_query_param(state, -1, json_autodetect)?);
+                }
+            }
+            LuaValue::Nil => {}
+            _ => {
+                return Err(format!(
+                    "transaction entry #{} params must be an array table",
+                    n + 1
+                ));
+            }
+        }
+
+        let label = match &entry.rawget("label").value {
+            LuaValue::String(label) => Some(String::from_utf8_lossy(label).into_owned()),
+            LuaValue::Nil => None,
+            _ => {
+                return Err(format!(
+                    "transaction entry #{} label must be a string",
+                    n + 1
+                ));
+            }
+        };
+
+        querys.push(DatabaseQuery {
+            sql,
+            binds,
+            options: RowsOptions {
+                label,
+                ..Default::default()
+            },
+        });
+    }
+    Ok(querys)
+}
+
+extern "C-unwind" fn transaction_from_table(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+
+    let index = args.iter_arg();
+    laux::lua_checktype(state, index, ffi::LUA_TTABLE);
+    // optional `{retry_on_deadlock = attempts}`
+    let opts = args.iter_arg();
+    let attempts = match (laux::lua_type(state, opts) == LuaType::Table)
+        .then(|| laux::opt_field::<i64>(state, opts, "retry_on_deadlock"))
+        .flatten()
+    {
+        Some(attempts) => match deadlock_attempts(attempts) {
+            Ok(attempts) => attempts,
+            Err(err) => return push_request_error(state, conn.raise_errors, err),
+        },
+        None => 1,
+    };
+    let querys = match table_to_querys(state, index, !conn.no_json_autodetect) {
+        Ok(querys) => querys,
+        Err(err) => return push_request_error(state, conn.raise_errors, err),
+    };
+
+    send_transaction(state, conn, owner, session, querys, attempts)
+}
+
+fn batch_querys(
+    state: LuaState,
+    sql: &str,
+    rows_index: i32,
+    json_autodetect: bool,
+) -> Result<Vec<DatabaseQuery>, String> {
+    let rows = LuaTable::from_stack(state, rows_index);
+    let mut querys = Vec::with_capacity(rows.len());
+    for (i, _) in rows.array_iter().enumerate() {
+        if laux::lua_type(state, -1) != LuaType::Table {
+            return Err(format!(
+                "execute_batch row #{} expects an array of params",
+                i + 1
+            ));
+        }
+        querys.push(DatabaseQuery {
+            sql: sql.to_string(),
+            binds: array_to_params(state, -1, json_autodetect)?,
+            options: RowsOptions::default(),
+        });
+    }
+    if querys.is_empty() {
+        return Err("execute_batch rows is empty".to_string());
+    }
+    Ok(querys)
+}
+
+extern "C-unwind" fn execute_batch(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+    let sql: &str = laux::lua_get(state, args.iter_arg());
+    let rows_index = args.iter_arg();
+    laux::lua_checktype(state, rows_index, ffi::LUA_TTABLE);
+
+    match batch_querys(state, sql, rows_index, !conn.no_json_autodetect) {
+        Ok(querys) => send_transaction(state, conn, owner, session, querys, 1),
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+fn send_transaction(
+    state: LuaState,
+    conn: &DatabaseConnection,
+    owner: u32,
+    session: i64,
+    querys: Vec<DatabaseQuery>,
+    attempts: u32,
+) -> i32 {
+    if let Err(err) = check_inflight(conn) {
+        drop(querys);
+        return push_request_error_kind(state, conn.raise_errors, "OVERLOADED", err);
+    }
+    match conn.tx.try_send(DatabaseRequest::Transaction(
+        owner, session, querys, 1, attempts,
+    )) {
+        Ok(_) => {
+            conn.counter
+                .fetch_add(1, std::sync::atomic::Ordering::Release);
+            push_accepted(state, session)
+        }
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+const IN_CLAUSE_CHUNK: usize = 1000;
+
+const EXPORT_CHUNK_ROWS: usize = 1000;
+
+/// Names are spliced into generated SQL
+fn check_identifier(name: &str) -> Result<&str, String> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+    {
+        Ok(name)
+    } else {
+        Err(format!("invalid identifier '{}'", name))
+    }
+}
+
+enum JsonPathSegment<'a> {
+    Key(&'a str),
+    Index(u32),
+}
+
+/// Keys are limited to identifier characters so the path literal needs no escaping
+fn parse_json_path(path: &str) -> Result<Vec<JsonPathSegment<'_>>, String> {
+    let invalid = || format!("invalid json path '{}'", path);
+    let body = path.strip_prefix('$').unwrap_or(path);
+    let body = body.strip_prefix('.').unwrap_or(body);
+    let mut segments = Vec::new();
+    for part in body.split('.') {
+        let (key, mut rest) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if !key.is_empty() {
+            if !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(invalid());
+            }
+            segments.push(JsonPathSegment::Key(key));
+        } else if rest.is_empty() {
+            return Err(invalid());
+        }
+        while !rest.is_empty() {
+            let end = rest.find(']').ok_or_else(invalid)?;
+            let index = rest[1..end].parse::<u32>().map_err(|_| invalid())?;
+            segments.push(JsonPathSegment::Index(index));
+            rest = &rest[end + 1..];
+            if !rest.is_empty() && !rest.starts_with('[') {
+                return Err(invalid());
+            }
+        }
+    }
+    Ok(segments)
+}
+
+fn json_path_sql(backend: &str, column: &str, path: &str) -> Result<String, String> {
+    let column = check_identifier(column)?;
+    let segments = parse_json_path(path)?;
+    if backend == "postgres" {
+        let keys = segments
+            .iter()
+            .map(|segment| match segment {
+                JsonPathSegment::Key(key) => key.to_string(),
+                JsonPathSegment::Index(index) => index.to_string(),
+            })
+            .collect::<Vec<_>>();
+        return Ok(format!("{} #>> '{{{}}}'", column, keys.join(",")));
+    }
+
+    let mut json_path = "$".to_string();
+    for segment in segments.iter() {
+        match segment {
+            JsonPathSegment::Key(key) => {
+                json_path.push('.');
+                json_path.push_str(key);
+            }
+            JsonPathSegment::Index(index) => json_path.push_str(&format!("[{}]", index)),
+        }
+    }
+    if backend == "mysql" {
+        Ok(format!("{} ->> '{}'", column, json_path))
+    } else {
+        Ok(format!("json_extract({}, '{}')", column, json_path))
+    }
+}
+
+fn placeholder(numbered: bool, n: usize) -> String {
+    if numbered {
+        format!("${}", n)
+    } else {
+        "?".to_string()
+    }
+}
+
+/// Fetches `limit + 1` rows, the extra one only tells whether a next page exists
+fn keyset_page_sql(
+    numbered: bool,
+    select: &str,
+    filter: Option<&str>,
+    key: &str,
+    after: Option<usize>,
+    limit: u32,
+    desc: bool,
+) -> Result<String, String> {
+    let key = check_identifier(key)?;
+    if limit == 0 {
+        return Err("page limit must be positive".to_string());
+    }
+
+    let mut conditions = Vec::new();
+    if let Some(filter) = filter.filter(|filter| !filter.trim().is_empty()) {
+        conditions.push(format!("({})", filter));
+    }
+    if let Some(n) = after {
+        let op = if desc { "<" } else { ">" };
+        conditions.push(format!("{} {} {}", key, op, placeholder(numbered, n)));
+    }
+
+    let mut sql = select.trim_end().to_string();
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    let order = if desc { "DESC" } else { "ASC" };
+    sql.push_str(&format!(
+        " ORDER BY {} {} LIMIT {}",
+        key,
+        order,
+        limit as u64 + 1
+    ));
+    Ok(sql)
+}
+
+fn array_to_params(
+    state: LuaState,
+    index: i32,
+    json_autodetect: bool,
+) -> Result<Vec<QueryParams>, String> {
+    let table = LuaTable::from_stack(state, index);
+    let mut params = Vec::with_capacity(table.len());
+    for _ in table.array_iter() {
+        params.push(get_query_param(state, -1, json_autodetect)?);
+    }
+    Ok(params)
+}
+
+fn in_clause_querys(
+    numbered: bool,
+    statement: &str,
+    set: &[QueryParams],
+    column: &str,
+    ids: &[QueryParams],
+) -> Vec<DatabaseQuery> {
+    ids.chunks(IN_CLAUSE_CHUNK)
+        .map(|chunk| {
+            let placeholders = (0..chunk.len())
+                .map(|i| placeholder(numbered, set.len() + i + 1))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut binds = set.to_vec();
+            binds.extend_from_slice(chunk);
+            DatabaseQuery {
+                sql: format!("{} WHERE {} IN ({})", statement, column, placeholders),
+                binds,
+                options: RowsOptions::default(),
+            }
+        })
+        .collect()
+}
+
+fn delete_in_querys(
+    state: LuaState,
+    conn: &DatabaseConnection,
+    table_name: &str,
+    column: &str,
+    ids_index: i32,
+) -> Result<Vec<DatabaseQuery>, String> {
+    let table_name = check_identifier(table_name)?;
+    let column = check_identifier(column)?;
+    let ids = array_to_params(state, ids_index, !conn.no_json_autodetect)?;
+    Ok(in_clause_querys(
+        conn.numbered_params,
+        &format!("DELETE FROM {}", table_name),
+        &[],
+        column,
+        &ids,
+    ))
+}
+
+fn update_in_querys(
+    state: LuaState,
+    conn: &DatabaseConnection,
+    table_name: &str,
+    set_index: i32,
+    column: &str,
+    ids_index: i32,
+) -> Result<Vec<DatabaseQuery>, String> {
+    let table_name = check_identifier(table_name)?;
+    let column = check_identifier(column)?;
+
+    let mut assignments = Vec::new();
+    let mut set = Vec::new();
+    for (key, _) in LuaTable::from_stack(state, set_index).iter() {
+        let LuaValue::String(name) = key else {
+            return Err("update_in set_map expects {column = value, ...}".to_string());
+        };
+        let name = check_identifier(std::str::from_utf8(name).unwrap_or_default())?;
+        assignments.push(format!(
+            "{} = {}",
+            name,
+            placeholder(conn.numbered_params, set.len() + 1)
+        ));
+        set.push(get_query_param(state, -1, !conn.no_json_autodetect)?);
+    }
+    if set.is_empty() {
+        return Err("update_in set_map is empty".to_string());
+    }
+
+    let ids = array_to_params(state, ids_index, !conn.no_json_autodetect)?;
+    Ok(in_clause_querys(
+        conn.numbered_params,
+        &format!("UPDATE {} SET {}", table_name, assignments.join(", ")),
+        &set,
+        column,
+        &ids,
+    ))
+}
+
+extern "C-unwind" fn delete_in(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+    let table_name: &str = laux::lua_get(state, args.iter_arg());
+    let column: &str = laux::lua_get(state, args.iter_arg());
+    let ids_index = args.iter_arg();
+    laux::lua_checktype(state, ids_index, ffi::LUA_TTABLE);
+
+    match delete_in_querys(state, conn, table_name, column, ids_index) {
+        Ok(querys) => send_transaction(state, conn, owner, session, querys, 1),
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+extern "C-unwind" fn update_in(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+    let table_name: &str = laux::lua_get(state, args.iter_arg());
+    let set_index = args.iter_arg();
+    laux::lua_checktype(state, set_index, ffi::LUA_TTABLE);
+    let column: &str = laux::lua_get(state, args.iter_arg());
+    let ids_index = args.iter_arg();
+    laux::lua_checktype(state, ids_index, ffi::LUA_TTABLE);
+
+    match update_in_querys(state, conn, table_name, set_index, column, ids_index) {
+        Ok(querys) => send_transaction(state, conn, owner, session, querys, 1),
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+extern "C-unwind" fn close(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
 
     match conn.tx.try_send(DatabaseRequest::Close()) {
         Ok(_) => {
             laux::lua_push(state, true);
             1
         }
-        Err(err) => {
-            push_lua_table!(
-                state,
-                "kind" => "ERROR",
-                "message" => err.to_string()
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+extern "C-unwind" fn prepare_describe(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, 2);
+    let session = laux::lua_get(state, 3);
+    let sql: &str = laux::lua_get(state, 4);
+
+    if let Err(err) = check_inflight(conn) {
+        return push_request_error_kind(state, conn.raise_errors, "OVERLOADED", err);
+    }
+    match conn
+        .tx
+        .try_send(DatabaseRequest::Describe(owner, session, sql.to_string()))
+    {
+        Ok(_) => {
+            conn.counter
+                .fetch_add(1, std::sync::atomic::Ordering::Release);
+            push_accepted(state, session)
+        }
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+extern "C-unwind" fn estimate_count(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, 2);
+    let session = laux::lua_get(state, 3);
+    let table = match check_identifier(laux::lua_get(state, 4)) {
+        // at most one schema qualifier, `a.b.c` would otherwise be split differently per backend
+        Ok(table) if table.split('.').count() > 2 || table.split('.').any(str::is_empty) => {
+            let err = format!(
+                "invalid table name '{}': expected table or schema.table",
+                table
             );
+            return push_request_error(state, conn.raise_errors, err);
+        }
+        Ok(table) => table.to_string(),
+        Err(err) => return push_request_error(state, conn.raise_errors, err),
+    };
+
+    if let Err(err) = check_inflight(conn) {
+        return push_request_error_kind(state, conn.raise_errors, "OVERLOADED", err);
+    }
+    match conn
+        .tx
+        .try_send(DatabaseRequest::EstimateCount(owner, session, table))
+    {
+        Ok(_) => {
+            conn.counter
+                .fetch_add(1, std::sync::atomic::Ordering::Release);
+            push_accepted(state, session)
+        }
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+extern "C-unwind" fn export(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, args.iter_arg())
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+    let path: &str = laux::lua_get(state, args.iter_arg());
+    let format = match ExportFormat::from_lua(state, args.iter_arg()) {
+        Ok(format) => format,
+        Err(err) => return push_request_error(state, conn.raise_errors, err),
+    };
+
+    // row decode options such as `encodings`, nil for the defaults
+    let mut options = match QueryOptions::from_lua(state, args.iter_arg()) {
+        Ok(options) => options,
+        Err(err) => return push_request_error(state, conn.raise_errors, err),
+    };
+    options.no_json_autodetect |= conn.no_json_autodetect;
+    options.backslash_escapes = conn.info.backend == "mysql";
+
+    if let Err(err) = check_inflight(conn) {
+        return push_request_error_kind(state, conn.raise_errors, "OVERLOADED", err);
+    }
+    let query = match read_query(state, options, args) {
+        Ok(query) => query,
+        Err(err) => return push_request_error(state, conn.raise_errors, err),
+    };
+
+    let request = ExportRequest {
+        query,
+        path: path.to_string(),
+        format,
+    };
+    match conn
+        .tx
+        .try_send(DatabaseRequest::Export(owner, session, request))
+    {
+        Ok(_) => {
+            conn.counter
+                .fetch_add(1, std::sync::atomic::Ordering::Release);
+            push_accepted(state, session)
+        }
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+extern "C-unwind" fn begin(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
+
+    let owner = laux::lua_get(state, 2);
+    let session = laux::lua_get(state, 3);
+
+    if let Err(err) = check_inflight(conn) {
+        return push_request_error_kind(state, conn.raise_errors, "OVERLOADED", err);
+    }
+    match conn.tx.try_send(DatabaseRequest::Begin(owner, session)) {
+        Ok(_) => {
+            conn.counter
+                .fetch_add(1, std::sync::atomic::Ordering::Release);
+            push_accepted(state, session)
+        }
+        Err(err) => push_request_error(state, conn.raise_errors, err),
+    }
+}
+
+fn send_session_request(
+    state: LuaState,
+    transaction: &TransactionSession,
+    session: i64,
+    op: SessionRequest,
+) -> i32 {
+    let err = match transaction.tx.try_send(op) {
+        Ok(_) => {
+            return push_accepted(state, session);
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => {
+            "transaction already committed or rolled back".to_string()
+        }
+        Err(err) => err.to_string(),
+    };
+    push_request_error(state, transaction.raise_errors, err)
+}
+
+extern "C-unwind" fn session_query(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let transaction = laux::lua_touserdata::<TransactionSession>(state, args.iter_arg())
+        .expect("Invalid transaction session pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+
+    let options = QueryOptions {
+        no_json_autodetect: transaction.no_json_autodetect,
+        backslash_escapes: transaction.backslash_escapes,
+        ..Default::default()
+    };
+    let query = match read_query(state, options, args) {
+        Ok(query) => query,
+        Err(err) => return push_request_error(state, transaction.raise_errors, err),
+    };
+    send_session_request(
+        state,
+        transaction,
+        session,
+        SessionRequest::Query(owner, session, query),
+    )
+}
+
+extern "C-unwind" fn session_query_with(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let transaction = laux::lua_touserdata::<TransactionSession>(state, args.iter_arg())
+        .expect("Invalid transaction session pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+
+    let query = match QueryOptions::from_lua(state, args.iter_arg()).and_then(|mut options| {
+        options.no_json_autodetect |= transaction.no_json_autodetect;
+        options.backslash_escapes = transaction.backslash_escapes;
+        read_query(state, options, args)
+    }) {
+        Ok(query) => query,
+        Err(err) => return push_request_error(state, transaction.raise_errors, err),
+    };
+    send_session_request(
+        state,
+        transaction,
+        session,
+        SessionRequest::Query(owner, session, query),
+    )
+}
+
+extern "C-unwind" fn session_execute_batch(state: LuaState) -> i32 {
+    let mut args = LuaArgs::new(1);
+    let transaction = laux::lua_touserdata::<TransactionSession>(state, args.iter_arg())
+        .expect("Invalid transaction session pointer");
+
+    let owner = laux::lua_get(state, args.iter_arg());
+    let session = laux::lua_get(state, args.iter_arg());
+    let sql: &str = laux::lua_get(state, args.iter_arg());
+    let rows_index = args.iter_arg();
+    laux::lua_checktype(state, rows_index, ffi::LUA_TTABLE);
+
+    match batch_querys(state, sql, rows_index, !transaction.no_json_autodetect) {
+        Ok(querys) => send_session_request(
+            state,
+            transaction,
+            session,
+            SessionRequest::Batch(owner, session, querys),
+        ),
+        Err(err) => push_request_error(state, transaction.raise_errors, err),
+    }
+}
+
+fn finish_session(state: LuaState, commit: bool) -> i32 {
+    let transaction = laux::lua_touserdata::<TransactionSession>(state, 1)
+        .expect("Invalid transaction session pointer");
+
+    let owner = laux::lua_get(state, 2);
+    let session = laux::lua_get(state, 3);
+    send_session_request(
+        state,
+        transaction,
+        session,
+        SessionRequest::Finish(owner, session, commit),
+    )
+}
+
+extern "C-unwind" fn session_commit(state: LuaState) -> i32 {
+    finish_session(state, true)
+}
+
+extern "C-unwind" fn session_rollback(state: LuaState) -> i32 {
+    finish_session(state, false)
+}
+
+fn push_transaction_session(state: LuaState, transaction: TransactionSession) -> i32 {
+    let l = [
+        lreg!("query", session_query),
+        lreg!("query_with", session_query_with),
+        lreg!("execute_batch", session_execute_batch),
+        lreg!("commit", session_commit),
+        lreg!("rollback", session_rollback),
+        lreg_null!(),
+    ];
+    if laux::lua_newuserdata(
+        state,
+        transaction,
+        cstr!("sqlx_transaction_session_metatable"),
+        l.as_ref(),
+    )
+    .is_none()
+    {
+        laux::lua_pushnil(state);
+    }
+    1
+}
+
+extern "C-unwind" fn json_path(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
+    let column: &str = laux::lua_get(state, 2);
+    let path: &str = laux::lua_get(state, 3);
+    match json_path_sql(conn.info.backend, column, path) {
+        Ok(sql) => {
+            laux::lua_push(state, sql.as_str());
+            1
+        }
+        Err(err) => laux::lua_error(state, err),
+    }
+}
+
+extern "C-unwind" fn keyset_page(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
+    let select: &str = laux::lua_get(state, 2);
+    let filter: Option<&str> = laux::lua_opt(state, 3);
+    let key: &str = laux::lua_get(state, 4);
+    let after: Option<usize> = laux::lua_opt(state, 5);
+    let limit: u32 = laux::lua_get(state, 6);
+    let desc: bool = laux::lua_opt(state, 7).unwrap_or(false);
+    match keyset_page_sql(
+        conn.numbered_params,
+        select,
+        filter,
+        key,
+        after,
+        limit,
+        desc,
+    ) {
+        Ok(sql) => {
+            laux::lua_push(state, sql.as_str());
+            1
+        }
+        Err(err) => laux::lua_error(state, err),
+    }
+}
+
+extern "C-unwind" fn raise_errors(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
+    laux::lua_push(state, conn.raise_errors);
+    1
+}
+
+extern "C-unwind" fn server_version(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
+    match conn.stats.server_version.lock().unwrap().as_ref() {
+        Some(version) => {
+            laux::lua_push(state, version.text.as_str());
+            laux::lua_push(state, version.number);
+            2
+        }
+        None => 0,
+    }
+}
+
+extern "C-unwind" fn cancel_owner(state: LuaState) -> i32 {
+    let conn = laux::lua_touserdata::<DatabaseConnection>(state, 1)
+        .expect("Invalid database connect pointer");
+    let owner: u32 = laux::lua_get(state, 2);
+
+    conn.cancels
+        .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+    match conn.tx.try_send(DatabaseRequest::CancelOwner(owner)) {
+        Ok(_) => {
+            laux::lua_push(state, true);
             1
         }
+        Err(err) => {
+            conn.cancels
+                .fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
+            push_request_error(state, conn.raise_errors, err)
+        }
     }
 }
 
@@ -538,11 +5928,25 @@ enum DbType {
     Text,
     Bool,
     Timestamp,
+    TimestampTz,
     Date,
     Time,
     Uuid,
     Bytes,
     Json,
+    Interval,
+    Money,
+    Geometry,
+    BytesArray,
+    JsonArray,
+    IntervalArray,
+    BoolArray,
+    CompositeArray,
+    TsVector,
+    TsQuery,
+    /// `"char"` of the catalogs, not `char(n)`
+    PgChar,
+    Regclass,
     Null,
     UnsupportedDecimal,
     UnsupportedTimeWithTz,
@@ -586,7 +5990,7 @@ static DB_TYPE_MAP: phf::Map<&'static str, DbType> = phf::phf_map! {
     "BOOLEAN" => DbType::Bool,
     // Timestamp types
     "TIMESTAMP" => DbType::Timestamp,
-    "TIMESTAMPTZ" => DbType::Timestamp,
+    "TIMESTAMPTZ" => DbType::TimestampTz,
     "DATETIME" => DbType::Timestamp,
     // Date type
     "DATE" => DbType::Date,
@@ -596,6 +6000,7 @@ static DB_TYPE_MAP: phf::Map<&'static str, DbType> = phf::phf_map! {
     "UUID" => DbType::Uuid,
     // Bytes types
     "BYTEA" => DbType::Bytes,
+    "BYTEA[]" => DbType::BytesArray,
     "BLOB" => DbType::Bytes,
     "VARBINARY" => DbType::Bytes,
     "BINARY" => DbType::Bytes,
@@ -605,12 +6010,33 @@ static DB_TYPE_MAP: phf::Map<&'static str, DbType> = phf::phf_map! {
     // Json types
     "JSON" => DbType::Json,
     "JSONB" => DbType::Json,
+    "JSON[]" => DbType::JsonArray,
+    "JSONB[]" => DbType::JsonArray,
+    // Interval type, decoded as seconds
+    "INTERVAL" => DbType::Interval,
+    "INTERVAL[]" => DbType::IntervalArray,
+    "BOOL[]" => DbType::BoolArray,
+    // Anonymous row arrays, named composite arrays are found by their type kind
+    "RECORD[]" => DbType::CompositeArray,
+    // Money type, decoded as a decimal string
+    "MONEY" => DbType::Money,
+    // Spatial types, decoded as WKT. PostGIS types are extensions and report their lowercase name
+    "GEOMETRY" => DbType::Geometry,
+    "GEOGRAPHY" => DbType::Geometry,
+    "geometry" => DbType::Geometry,
+    "geography" => DbType::Geometry,
+    // Full-text search types, decoded as text
+    "TSVECTOR" => DbType::TsVector,
+    "TSQUERY" => DbType::TsQuery,
+    "tsvector" => DbType::TsVector,
+    "tsquery" => DbType::TsQuery,
+    // Postgres' internal single byte type, sqlx reports it quoted to tell it apart from char(n)
+    "\"CHAR\"" => DbType::PgChar,
     // Null type
     "NULL" => DbType::Null,
     // Unsupported decimal types
     "DECIMAL" => DbType::UnsupportedDecimal,
     "NUMERIC" => DbType::UnsupportedDecimal,
-    "MONEY" => DbType::UnsupportedDecimal,
     // Unsupported time with timezone
     "TIMETZ" => DbType::UnsupportedTimeWithTz,
     // Unsigned types
@@ -619,18 +6045,199 @@ static DB_TYPE_MAP: phf::Map<&'static str, DbType> = phf::phf_map! {
     "INT UNSIGNED" => DbType::UInt32,
     "MEDIUMINT UNSIGNED" => DbType::UInt32,
     "BIGINT UNSIGNED" => DbType::UInt64,
+    // Postgres unsigned 32-bit system types
+    "OID" => DbType::UInt32,
+    "XID" => DbType::UInt32,
+    "CID" => DbType::UInt32,
+    // not a sqlx builtin, named as pg_type spells it
+    "regclass" => DbType::Regclass,
 };
 
-impl DbType {
-    #[inline]
-    fn from_name(name: &str) -> Self {
-        DB_TYPE_MAP.get(name).copied().unwrap_or(Self::Unknown)
+impl DbType {
+    #[inline]
+    fn from_name(name: &str) -> Self {
+        DB_TYPE_MAP.get(name).copied().unwrap_or(Self::Unknown)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DbType::Int8 => "int8",
+            DbType::UInt8 => "uint8",
+            DbType::Int16 => "int16",
+            DbType::UInt16 => "uint16",
+            DbType::Int32 => "int32",
+            DbType::UInt32 => "uint32",
+            DbType::Int64 => "int64",
+            DbType::UInt64 => "uint64",
+            DbType::Float32 => "float32",
+            DbType::Float64 => "float64",
+            DbType::Text => "text",
+            DbType::Bool => "bool",
+            DbType::Timestamp => "timestamp",
+            DbType::TimestampTz => "timestamptz",
+            DbType::Date => "date",
+            DbType::Time => "time",
+            DbType::Uuid => "uuid",
+            DbType::Bytes => "bytes",
+            DbType::Json => "json",
+            DbType::Interval => "interval",
+            DbType::Money => "money",
+            DbType::Geometry => "geometry",
+            DbType::BytesArray => "bytes[]",
+            DbType::JsonArray => "json[]",
+            DbType::IntervalArray => "interval[]",
+            DbType::BoolArray => "bool[]",
+            DbType::CompositeArray => "record[]",
+            DbType::TsVector => "tsvector",
+            DbType::TsQuery => "tsquery",
+            DbType::PgChar => "char",
+            DbType::Regclass => "regclass",
+            DbType::Null => "null",
+            DbType::UnsupportedDecimal => "decimal",
+            DbType::UnsupportedTimeWithTz => "timetz",
+            DbType::Unknown => "unknown",
+        }
+    }
+}
+
+/// The fraction only when there is one, so `TIME(6)` keeps its microseconds
+fn format_time(time: NaiveTime) -> String {
+    time.format("%H:%M:%S%.f").to_string()
+}
+
+fn format_timestamp(dt: NaiveDateTime, options: &DecodeOptions) -> String {
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    match options.display_tz {
+        Some(tz) => tz.from_utc_datetime(&dt).format(FORMAT).to_string(),
+        None => dt.format(FORMAT).to_string(),
+    }
+}
+
+type ColumnInfo<'a> = Vec<(usize, &'a str, DbType)>;
+
+fn column_info<'r, DB: DecodeExtra>(
+    row: &'r <DB as Database>::Row,
+    columns: Option<&[String]>,
+) -> ColumnInfo<'r> {
+    row.columns()
+        .iter()
+        .enumerate()
+        .filter(|(_, column)| {
+            columns.is_none_or(|columns| columns.iter().any(|name| name == column.name()))
+        })
+        .map(|(index, column)| {
+            let name = column.name();
+            let db_type = DB::db_type(column.type_info());
+            (index, name, db_type)
+        })
+        .collect()
+}
+
+fn push_default(state: LuaState, value: &QueryParams, numbers: JsonNumbers) -> Result<(), String> {
+    match value {
+        QueryParams::Bool(value) => laux::lua_push(state, *value),
+        QueryParams::Int(value) => laux::lua_push(state, *value),
+        QueryParams::Float(value) | QueryParams::Interval(value) => laux::lua_push(state, *value),
+        QueryParams::Text(value)
+        | QueryParams::Record { literal: value, .. }
+        | QueryParams::TsQuery(value) => laux::lua_push(state, value.as_str()),
+        QueryParams::Bytes(value) => laux::lua_push(state, value.as_slice()),
+        QueryParams::Time(time) => laux::lua_push(state, format_time(*time)),
+        QueryParams::Decimal(value) => laux::lua_push(state, value.to_string()),
+        QueryParams::Null => laux::lua_pushnil(state),
+        QueryParams::BytesArray(values) => {
+            let array = LuaTable::new(state, values.len(), 0);
+            for value in values {
+                array.push(value.as_slice());
+            }
+        }
+        QueryParams::IntervalArray(values) => {
+            let array = LuaTable::new(state, values.len(), 0);
+            for value in values {
+                array.push(*value);
+            }
+        }
+        QueryParams::BoolArray(values) => {
+            let array = LuaTable::new(state, values.len(), 0);
+            for (i, value) in values.iter().enumerate() {
+                match value {
+                    Some(value) => laux::lua_push(state, *value),
+                    None => laux::lua_pushlightuserdata(state, std::ptr::null_mut()),
+                }
+                array.rawseti(i + 1);
+            }
+        }
+        QueryParams::Json(value) | QueryParams::JsonText(value) | QueryParams::Jsonb(value) => {
+            push_json(state, value, numbers)?
+        }
+    }
+    Ok(())
+}
+
+/// `null` becomes `json.null` like `json.decode`
+fn push_json(
+    state: LuaState,
+    value: &serde_json::Value,
+    numbers: JsonNumbers,
+) -> Result<(), String> {
+    match value {
+        serde_json::Value::Object(map) => {
+            ensure_row_stack(state, 0)?;
+            let table = LuaTable::new(state, 0, map.len());
+            for (k, v) in map {
+                laux::lua_push(state, k.as_str());
+                push_json(state, v, numbers)?;
+                table.insert_from_stack();
+            }
+        }
+        serde_json::Value::Array(arr) => {
+            ensure_row_stack(state, 0)?;
+            let table = LuaTable::new(state, arr.len(), 0);
+            for (i, v) in arr.iter().enumerate() {
+                push_json(state, v, numbers)?;
+                table.rawseti(i + 1);
+            }
+        }
+        serde_json::Value::Number(n) => match (numbers, n.as_i64()) {
+            (JsonNumbers::Preserve, Some(v)) => laux::lua_push(state, v),
+            // only u64 above i64::MAX reaches here as an integer
+            (JsonNumbers::Preserve, None) if n.is_u64() => laux::lua_push(state, n.to_string()),
+            _ => laux::lua_push(state, n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(v) => laux::lua_push(state, v.as_str()),
+        serde_json::Value::Bool(v) => laux::lua_push(state, *v),
+        serde_json::Value::Null => laux::lua_pushlightuserdata(state, std::ptr::null_mut()),
+    }
+    Ok(())
+}
+
+/// Row and rows tables, the key/value being inserted and scratch for the backend decoders
+const ROW_STACK_EXTRA: usize = 6;
+
+fn ensure_row_stack(state: LuaState, columns: usize) -> Result<(), String> {
+    let needed = columns.saturating_add(ROW_STACK_EXTRA);
+    let grown = i32::try_from(needed)
+        .is_ok_and(|slots| unsafe { ffi::lua_checkstack(state.as_ptr(), slots) } != 0);
+    if grown {
+        Ok(())
+    } else {
+        Err(format!(
+            "Lua stack can't grow to {} slots for a {} column row",
+            needed, columns
+        ))
     }
 }
 
-fn process_rows<'a, DB>(state: LuaState, rows: &'a [<DB as Database>::Row]) -> Result<i32, String>
+/// Returns 2 with `false, message` pushed when a column can't be read
+fn push_row<'a, DB>(
+    state: LuaState,
+    row: &'a <DB as Database>::Row,
+    column_info: &ColumnInfo<'_>,
+    rows_options: &RowsOptions,
+    options: &DecodeOptions,
+) -> Result<i32, String>
 where
-    DB: sqlx::Database,
+    DB: DecodeExtra,
     usize: ColumnIndex<<DB as Database>::Row>,
     i8: sqlx::Decode<'a, DB>,
     i16: sqlx::Decode<'a, DB>,
@@ -646,178 +6253,627 @@ where
     NaiveTime: sqlx::Decode<'a, DB>,
     Uuid: sqlx::Decode<'a, DB>,
 {
-    let table = LuaTable::new(state, rows.len(), 0);
-    if rows.is_empty() {
-        return Ok(1);
-    }
-
-    let column_info: Vec<(usize, &str, DbType)> = rows
-        .first()
-        .unwrap()
-        .columns()
-        .iter()
-        .enumerate()
-        .map(|(index, column)| {
-            let name = column.name();
-            let db_type = DbType::from_name(column.type_info().name());
-            (index, name, db_type)
-        })
-        .collect();
-
-    for (i, row) in rows.iter().enumerate() {
-        let row_table = LuaTable::new(state, 0, row.len());
-        for (index, column_name, db_type) in column_info.iter() {
-            match row.try_get_raw(*index) {
-                Ok(value) => {
-                    if value.is_null() {
-                        row_table.insert(*column_name, LuaNil {});
-                        continue;
-                    }
+    ensure_row_stack(state, column_info.len())?;
+    let row_table = LuaTable::new(state, 0, column_info.len());
+    fill_row::<DB>(state, &row_table, row, column_info, rows_options, options)
+}
 
-                    match db_type {
-                        DbType::Int8 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0i8);
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::UInt8 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0i8) as u8;
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Int16 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0i16);
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::UInt16 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0i16) as u16;
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Int32 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0i32);
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::UInt32 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0i32) as u32;
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Int64 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0i64);
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::UInt64 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0i64) as u64;
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Float32 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0.0f32);
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Float64 => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(0.0f64);
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Text => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or("");
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Bool => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or(false);
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Timestamp => {
-                            match <NaiveDateTime as sqlx::decode::Decode<DB>>::decode(value) {
-                                Ok(dt) => {
-                                    row_table.insert(
-                                        *column_name,
-                                        dt.format("%Y-%m-%d %H:%M:%S").to_string(),
-                                    );
-                                }
-                                Err(_) => {
-                                    row_table.insert(*column_name, LuaNil {});
-                                }
-                            }
+/// NULL columns are cleared so a table can be reused across rows
+fn fill_row<'a, DB>(
+    state: LuaState,
+    row_table: &LuaTable,
+    row: &'a <DB as Database>::Row,
+    column_info: &ColumnInfo<'_>,
+    rows_options: &RowsOptions,
+    options: &DecodeOptions,
+) -> Result<i32, String>
+where
+    DB: DecodeExtra,
+    usize: ColumnIndex<<DB as Database>::Row>,
+    i8: sqlx::Decode<'a, DB>,
+    i16: sqlx::Decode<'a, DB>,
+    i32: sqlx::Decode<'a, DB>,
+    i64: sqlx::Decode<'a, DB>,
+    f32: sqlx::Decode<'a, DB>,
+    f64: sqlx::Decode<'a, DB>,
+    bool: sqlx::Decode<'a, DB>,
+    &'a str: sqlx::Decode<'a, DB>,
+    &'a [u8]: sqlx::Decode<'a, DB>,
+    NaiveDate: sqlx::Decode<'a, DB>,
+    NaiveDateTime: sqlx::Decode<'a, DB>,
+    NaiveTime: sqlx::Decode<'a, DB>,
+    Uuid: sqlx::Decode<'a, DB>,
+{
+    for (index, column_name, db_type) in column_info.iter() {
+        match row.try_get_raw(*index) {
+            Ok(value) => {
+                if value.is_null() {
+                    match rows_options
+                        .defaults
+                        .iter()
+                        .find(|(column, _)| column == column_name)
+                    {
+                        Some((_, default)) => {
+                            laux::lua_push(state, *column_name);
+                            push_default(state, default, rows_options.json_numbers)?;
+                            row_table.insert_from_stack();
                         }
-                        DbType::Date => {
-                            match <NaiveDate as sqlx::decode::Decode<DB>>::decode(value) {
-                                Ok(date) => {
-                                    row_table.insert(*column_name, date.format("%Y-%m-%d").to_string());
-                                }
-                                Err(_) => {
-                                    row_table.insert(*column_name, LuaNil {});
-                                }
+                        None => match &rows_options.nulls {
+                            NullCells::Omit => {
+                                row_table.insert(*column_name, LuaNil {});
                             }
-                        }
-                        DbType::Time => {
-                            match <NaiveTime as sqlx::decode::Decode<DB>>::decode(value) {
-                                Ok(time) => {
-                                    row_table.insert(*column_name, time.format("%H:%M:%S").to_string());
-                                }
-                                Err(_) => {
-                                    row_table.insert(*column_name, LuaNil {});
-                                }
+                            NullCells::JsonNull => {
+                                laux::lua_push(state, *column_name);
+                                laux::lua_pushlightuserdata(state, std::ptr::null_mut());
+                                row_table.insert_from_stack();
                             }
-                        }
-                        DbType::Uuid => {
-                            match <Uuid as sqlx::decode::Decode<DB>>::decode(value) {
-                                Ok(uuid) => {
-                                    row_table.insert(*column_name, uuid.to_string());
-                                }
-                                Err(_) => {
-                                    row_table.insert(*column_name, LuaNil {});
-                                }
+                            NullCells::Sentinel(sentinel) => {
+                                row_table.insert(*column_name, sentinel.as_str());
                             }
-                        }
-                        DbType::Bytes => {
-                            let v: &[u8] = sqlx::decode::Decode::decode(value).unwrap_or(b"");
-                            row_table.insert(*column_name, v);
-                        }
-                        DbType::Json => {
-                            let v = sqlx::decode::Decode::decode(value).unwrap_or("{}");
+                        },
+                    }
+                    continue;
+                }
+
+                match decode_cell::<DB>(value, *db_type, column_name, rows_options, options)? {
+                    Cell::Nil => {
+                        row_table.insert(*column_name, LuaNil {});
+                    }
+                    Cell::Int(v) => {
+                        row_table.insert(*column_name, v);
+                    }
+                    Cell::UInt(v) => {
+                        row_table.insert(*column_name, v);
+                    }
+                    Cell::Float(v) => {
+                        row_table.insert(*column_name, v);
+                    }
+                    Cell::Bool(v) => {
+                        row_table.insert(*column_name, v);
+                    }
+                    Cell::Text(v) => {
+                        row_table.insert(*column_name, v);
+                    }
+                    Cell::String(v) => {
+                        row_table.insert(*column_name, v);
+                    }
+                    Cell::Bytes(v) | Cell::Raw(v) => {
+                        row_table.insert(*column_name, v);
+                    }
+                    Cell::Json(v) => {
+                        if rows_options.json_as_table
+                            && let Ok(json) = serde_json::from_str::<serde_json::Value>(v)
+                        {
+                            laux::lua_push(state, *column_name);
+                            push_json(state, &json, rows_options.json_numbers)?;
+                            row_table.insert_from_stack();
+                        } else {
                             row_table.insert(*column_name, v);
                         }
-                        DbType::Null => {
-                            row_table.insert(*column_name, LuaNil {});
-                        }
-                        DbType::UnsupportedDecimal => {
-                            return Err(format!(
-                                "Unsupported decimal type for column '{}'",
-                                column_name
-                            ));
-                        }
-                        DbType::UnsupportedTimeWithTz => {
-                            return Err(format!(
-                                "Unsupported time with time zone type for column '{}'",
-                                column_name
-                            ));
-                        }
-                        DbType::Unknown => {
-                            if let Ok(bytes) = sqlx::decode::Decode::decode(value) {
-                                row_table.insert::<&str, &[u8]>(*column_name, bytes);
-                            } else {
-                                row_table.insert(*column_name, LuaNil {});
-                            }
+                    }
+                    Cell::Extra(value) => {
+                        DB::insert_extra(
+                            state,
+                            row_table,
+                            column_name,
+                            value,
+                            *db_type,
+                            rows_options,
+                        )?;
+                    }
+                }
+            }
+            Err(error) => {
+                laux::lua_push(state, false);
+                laux::lua_push(state, format!("{} decode error: {}", column_name, error));
+                return Ok(2);
+            }
+        }
+    }
+    Ok(1)
+}
+
+#[derive(Clone, Copy)]
+enum ExportFormat {
+    Csv { delimiter: char, header: bool },
+    JsonLines,
+}
+
+impl ExportFormat {
+    fn from_lua(state: LuaState, index: i32) -> Result<Self, String> {
+        let (format, delimiter, header) = match laux::lua_type(state, index) {
+            LuaType::String => (laux::lua_get::<&str>(state, index), None, None),
+            LuaType::Table => (
+                laux::opt_field::<&str>(state, index, "format").unwrap_or("csv"),
+                laux::opt_field::<&str>(state, index, "delimiter"),
+                laux::opt_field::<bool>(state, index, "header"),
+            ),
+            _ => return Err("export format expects 'csv', 'jsonl' or an options table".to_string()),
+        };
+        match format {
+            "csv" => {
+                let delimiter = match delimiter.map(|text| {
+                    let mut chars = text.chars();
+                    (chars.next(), chars.next())
+                }) {
+                    None => ',',
+                    Some((Some(delimiter), None)) if !matches!(delimiter, '"' | '\r' | '\n') => {
+                        delimiter
+                    }
+                    Some(_) => {
+                        return Err(
+                            "export delimiter expects one character other than a quote or newline"
+                                .to_string(),
+                        );
+                    }
+                };
+                Ok(ExportFormat::Csv {
+                    delimiter,
+                    header: header.unwrap_or(true),
+                })
+            }
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            other => Err(format!(
+                "export format expects 'csv' or 'jsonl', got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+struct ExportRequest {
+    query: DatabaseQuery,
+    path: String,
+    format: ExportFormat,
+}
+
+struct ExportWriter {
+    file: tokio::io::BufWriter<tokio::fs::File>,
+    format: ExportFormat,
+    columns: Option<Vec<(String, DbType)>>,
+    line: String,
+    count: u64,
+}
+
+impl ExportWriter {
+    async fn create(request: &ExportRequest) -> Result<Self, sqlx::Error> {
+        let file = tokio::fs::File::create(&request.path)
+            .await
+            .map_err(|err| {
+                sqlx::Error::Io(std::io::Error::new(
+                    err.kind(),
+                    format!("export to '{}': {}", request.path, err),
+                ))
+            })?;
+        Ok(Self {
+            file: tokio::io::BufWriter::new(file),
+            format: request.format,
+            columns: None,
+            line: String::new(),
+            count: 0,
+        })
+    }
+
+    async fn write_row<'a, DB>(
+        &mut self,
+        row: &'a <DB as Database>::Row,
+        rows_options: &RowsOptions,
+        decode: &DecodeOptions,
+    ) -> Result<(), sqlx::Error>
+    where
+        DB: DecodeExtra,
+        usize: ColumnIndex<<DB as Database>::Row>,
+        i8: sqlx::Decode<'a, DB>,
+        i16: sqlx::Decode<'a, DB>,
+        i32: sqlx::Decode<'a, DB>,
+        i64: sqlx::Decode<'a, DB>,
+        f32: sqlx::Decode<'a, DB>,
+        f64: sqlx::Decode<'a, DB>,
+        bool: sqlx::Decode<'a, DB>,
+        &'a str: sqlx::Decode<'a, DB>,
+        &'a [u8]: sqlx::Decode<'a, DB>,
+        NaiveDate: sqlx::Decode<'a, DB>,
+        NaiveDateTime: sqlx::Decode<'a, DB>,
+        NaiveTime: sqlx::Decode<'a, DB>,
+        Uuid: sqlx::Decode<'a, DB>,
+    {
+        self.line.clear();
+        let columns = self.columns.get_or_insert_with(|| {
+            column_info::<DB>(row, None)
+                .into_iter()
+                .map(|(_, name, db_type)| (name.to_string(), db_type))
+                .collect()
+        });
+        if self.count == 0
+            && let ExportFormat::Csv {
+                delimiter,
+                header: true,
+            } = self.format
+        {
+            for (i, (name, _)) in columns.iter().enumerate() {
+                if i > 0 {
+                    self.line.push(delimiter);
+                }
+                push_csv_field(&mut self.line, name, delimiter);
+            }
+            self.line.push('\n');
+        }
+
+        if matches!(self.format, ExportFormat::JsonLines) {
+            self.line.push('{');
+        }
+        for (index, (name, db_type)) in columns.iter().enumerate() {
+            let value = row.try_get_raw(index).map_err(|err| {
+                sqlx::Error::Decode(format!("{} decode error: {}", name, err).into())
+            })?;
+            let value = if value.is_null() {
+                serde_json::Value::Null
+            } else {
+                let cell = decode_cell::<DB>(value, *db_type, name, rows_options, decode)
+                    .map_err(|err| sqlx::Error::Decode(err.into()))?;
+                cell_json(cell, *db_type, rows_options)
+            };
+            match self.format {
+                ExportFormat::Csv { delimiter, .. } => {
+                    if index > 0 {
+                        self.line.push(delimiter);
+                    }
+                    match &value {
+                        serde_json::Value::Null => {}
+                        serde_json::Value::String(text) => {
+                            push_csv_field(&mut self.line, text, delimiter)
                         }
+                        other => push_csv_field(&mut self.line, &other.to_string(), delimiter),
+                    }
+                }
+                ExportFormat::JsonLines => {
+                    if index > 0 {
+                        self.line.push(',');
                     }
+                    self.line
+                        .push_str(&serde_json::Value::from(name.as_str()).to_string());
+                    self.line.push(':');
+                    self.line.push_str(&value.to_string());
                 }
-                Err(error) => {
-                    laux::lua_push(state, false);
-                    laux::lua_push(state, format!("{} decode error: {}", column_name, error));
-                    return Ok(2);
+            }
+        }
+        if matches!(self.format, ExportFormat::JsonLines) {
+            self.line.push('}');
+        }
+        self.line.push('\n');
+
+        self.file.write_all(self.line.as_bytes()).await?;
+        self.count += 1;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<u64, sqlx::Error> {
+        self.file.flush().await?;
+        Ok(self.count)
+    }
+}
+
+fn push_csv_field(line: &mut String, field: &str, delimiter: char) {
+    if !field.contains([delimiter, '"', '\r', '\n']) {
+        line.push_str(field);
+        return;
+    }
+    line.push('"');
+    line.push_str(&field.replace('"', "\"\""));
+    line.push('"');
+}
+
+fn bytes_text(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(2 + bytes.len() * 2);
+    text.push_str("\\x");
+    for b in bytes {
+        text.push_str(&format!("{:02x}", b));
+    }
+    text
+}
+
+enum Cell<'a, DB: Database> {
+    Nil,
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    Text(&'a str),
+    String(String),
+    Bytes(&'a [u8]),
+    Raw(&'a [u8]),
+    Json(&'a str),
+    Extra(<DB as Database>::ValueRef<'a>),
+}
+
+fn decode_cell<'a, DB>(
+    value: <DB as Database>::ValueRef<'a>,
+    db_type: DbType,
+    column: &str,
+    rows_options: &RowsOptions,
+    options: &DecodeOptions,
+) -> Result<Cell<'a, DB>, String>
+where
+    DB: DecodeExtra,
+    i8: sqlx::Decode<'a, DB>,
+    i16: sqlx::Decode<'a, DB>,
+    i32: sqlx::Decode<'a, DB>,
+    i64: sqlx::Decode<'a, DB>,
+    f32: sqlx::Decode<'a, DB>,
+    f64: sqlx::Decode<'a, DB>,
+    bool: sqlx::Decode<'a, DB>,
+    &'a str: sqlx::Decode<'a, DB>,
+    &'a [u8]: sqlx::Decode<'a, DB>,
+    NaiveDate: sqlx::Decode<'a, DB>,
+    NaiveDateTime: sqlx::Decode<'a, DB>,
+    NaiveTime: sqlx::Decode<'a, DB>,
+    Uuid: sqlx::Decode<'a, DB>,
+{
+    if DB::overrides(db_type) {
+        return Ok(Cell::Extra(value));
+    }
+
+    if matches!(db_type, DbType::Text | DbType::Bytes)
+        && let Some((_, encoding)) = rows_options.encodings.iter().find(|(c, _)| c == column)
+    {
+        // raw bytes, the &str decode rejects anything that isn't valid UTF-8
+        let bytes: &[u8] = sqlx::decode::Decode::decode(value).unwrap_or(b"");
+        let (text, _) = encoding.decode_without_bom_handling(bytes);
+        return Ok(Cell::String(text.into_owned()));
+    }
+
+    Ok(match db_type {
+        DbType::Int8 => Cell::Int(sqlx::decode::Decode::decode(value).unwrap_or(0i8) as i64),
+        DbType::UInt8 => Cell::Int(sqlx::decode::Decode::decode(value).unwrap_or(0i8) as u8 as i64),
+        DbType::Int16 => Cell::Int(sqlx::decode::Decode::decode(value).unwrap_or(0i16) as i64),
+        DbType::UInt16 => {
+            Cell::Int(sqlx::decode::Decode::decode(value).unwrap_or(0i16) as u16 as i64)
+        }
+        DbType::Int32 => Cell::Int(sqlx::decode::Decode::decode(value).unwrap_or(0i32) as i64),
+        DbType::UInt32 => {
+            Cell::Int(sqlx::decode::Decode::decode(value).unwrap_or(0i32) as u32 as i64)
+        }
+        DbType::Regclass => Cell::String(
+            options.relation_name(sqlx::decode::Decode::decode(value).unwrap_or(0i32) as u32),
+        ),
+        DbType::Int64 => Cell::Int(sqlx::decode::Decode::decode(value).unwrap_or(0i64)),
+        DbType::UInt64 => Cell::UInt(sqlx::decode::Decode::decode(value).unwrap_or(0i64) as u64),
+        DbType::Float32 => {
+            Cell::Float(sqlx::decode::Decode::decode(value).unwrap_or(0.0f32) as f64)
+        }
+        DbType::Float64 => Cell::Float(sqlx::decode::Decode::decode(value).unwrap_or(0.0f64)),
+        DbType::Text => Cell::Text(sqlx::decode::Decode::decode(value).unwrap_or("")),
+        DbType::Bool => Cell::Bool(sqlx::decode::Decode::decode(value).unwrap_or(false)),
+        DbType::Timestamp | DbType::TimestampTz => {
+            match <NaiveDateTime as sqlx::decode::Decode<DB>>::decode(value) {
+                Ok(dt) => Cell::String(format_timestamp(dt, options)),
+                Err(_) => Cell::Nil,
+            }
+        }
+        DbType::Date => match <NaiveDate as sqlx::decode::Decode<DB>>::decode(value) {
+            Ok(date) => Cell::String(date.format("%Y-%m-%d").to_string()),
+            Err(_) => Cell::Nil,
+        },
+        DbType::Time => match <NaiveTime as sqlx::decode::Decode<DB>>::decode(value) {
+            Ok(time) => Cell::String(format_time(time)),
+            Err(_) => Cell::Nil,
+        },
+        DbType::Uuid => match <Uuid as sqlx::decode::Decode<DB>>::decode(value) {
+            Ok(uuid) => Cell::String(uuid.to_string()),
+            Err(_) => Cell::Nil,
+        },
+        DbType::Bytes => {
+            let bytes: &[u8] = sqlx::decode::Decode::decode(value).unwrap_or(b"");
+            match <[u8; 16]>::try_from(bytes) {
+                Ok(uuid) if rows_options.uuid_columns.iter().any(|c| c == column) => {
+                    let uuid = if rows_options.uuid_swap {
+                        uuid_unswap(uuid)
+                    } else {
+                        uuid
+                    };
+                    Cell::String(Uuid::from_bytes(uuid).to_string())
                 }
+                _ => Cell::Bytes(bytes),
             }
         }
+        DbType::Json => Cell::Json(sqlx::decode::Decode::decode(value).unwrap_or("{}")),
+        DbType::Interval
+        | DbType::Money
+        | DbType::Geometry
+        | DbType::BytesArray
+        | DbType::JsonArray
+        | DbType::IntervalArray
+        | DbType::BoolArray
+        | DbType::CompositeArray
+        | DbType::TsVector
+        | DbType::TsQuery
+        | DbType::PgChar => Cell::Extra(value),
+        DbType::UnsupportedDecimal => {
+            return Err(format!(
+                "Unsupported decimal type for column '{}', cast it to text",
+                column
+            ));
+        }
+        DbType::UnsupportedTimeWithTz => {
+            return Err(format!(
+                "Unsupported time with time zone type for column '{}', cast it to text",
+                column
+            ));
+        }
+        // MySQL and Postgres only report the column's declared type, so a value in a column
+        // typed NULL (e.g. a UNION whose first branch selects a NULL literal) or an unrecognized
+        // type comes back as its raw value rather than dropped
+        DbType::Unknown | DbType::Null => match sqlx::decode::Decode::decode(value) {
+            Ok(bytes) => Cell::Raw(bytes),
+            Err(_) => Cell::Nil,
+        },
+    })
+}
+
+fn cell_json<DB: DecodeExtra>(
+    cell: Cell<'_, DB>,
+    db_type: DbType,
+    rows_options: &RowsOptions,
+) -> serde_json::Value {
+    use serde_json::Value;
+
+    match cell {
+        Cell::Nil => Value::Null,
+        Cell::Int(v) => Value::from(v),
+        Cell::UInt(v) => Value::from(v),
+        Cell::Float(v) => Value::from(v),
+        Cell::Bool(v) => Value::from(v),
+        Cell::Text(text) => Value::from(text),
+        Cell::String(text) => Value::from(text),
+        Cell::Bytes(bytes) => Value::from(bytes_text(bytes)),
+        Cell::Raw(bytes) => match std::str::from_utf8(bytes) {
+            Ok(text) => Value::from(text),
+            Err(_) => Value::from(bytes_text(bytes)),
+        },
+        Cell::Json(text) => serde_json::from_str(text).unwrap_or_else(|_| Value::from(text)),
+        Cell::Extra(value) => DB::extra_json(value, db_type, rows_options),
+    }
+}
+
+/// `{ columns, stride, rows, v1, v2, ... }`, row `r` (0-based) column `c` at `r * stride + c`.
+/// NULLs are `json.null` so `#` is `rows * stride`.
+fn push_flat_rows<'a, DB>(
+    state: LuaState,
+    rows: &'a [<DB as Database>::Row],
+    rows_options: &RowsOptions,
+    options: &DecodeOptions,
+) -> Result<i32, String>
+where
+    DB: DecodeExtra,
+    usize: ColumnIndex<<DB as Database>::Row>,
+    i8: sqlx::Decode<'a, DB>,
+    i16: sqlx::Decode<'a, DB>,
+    i32: sqlx::Decode<'a, DB>,
+    i64: sqlx::Decode<'a, DB>,
+    f32: sqlx::Decode<'a, DB>,
+    f64: sqlx::Decode<'a, DB>,
+    bool: sqlx::Decode<'a, DB>,
+    &'a str: sqlx::Decode<'a, DB>,
+    &'a [u8]: sqlx::Decode<'a, DB>,
+    NaiveDate: sqlx::Decode<'a, DB>,
+    NaiveDateTime: sqlx::Decode<'a, DB>,
+    NaiveTime: sqlx::Decode<'a, DB>,
+    Uuid: sqlx::Decode<'a, DB>,
+{
+    let column_info = rows.first().map_or_else(Vec::new, |row| {
+        column_info::<DB>(row, rows_options.columns.as_deref())
+    });
+    let stride = column_info.len();
+    let table = LuaTable::new(state, rows.len() * stride, 3);
+    laux::lua_push(state, "columns");
+    let columns = LuaTable::new(state, stride, 0);
+    for (_, column_name, _) in column_info.iter() {
+        columns.push(*column_name);
+    }
+    table.insert_from_stack();
+    table.insert("stride", stride);
+    table.insert("rows", rows.len());
+
+    ensure_row_stack(state, stride)?;
+    let scratch = LuaTable::new(state, 0, stride);
+    for (r, row) in rows.iter().enumerate() {
+        let n = fill_row::<DB>(state, &scratch, row, &column_info, rows_options, options)?;
+        if n != 1 {
+            return Ok(n);
+        }
+        for (c, (_, column_name, _)) in column_info.iter().enumerate() {
+            laux::lua_push(state, *column_name);
+            let cell = unsafe { ffi::lua_rawget(state.as_ptr(), scratch.index()) };
+            if cell == ffi::LUA_TNIL {
+                laux::lua_pop(state, 1);
+                laux::lua_pushlightuserdata(state, std::ptr::null_mut());
+            }
+            table.rawseti(r * stride + c + 1);
+        }
+    }
+    laux::lua_pop(state, 1);
+    Ok(1)
+}
+
+fn process_rows<'a, DB>(
+    state: LuaState,
+    rows: &'a [<DB as Database>::Row],
+    rows_options: &RowsOptions,
+    options: &DecodeOptions,
+) -> Result<i32, String>
+where
+    DB: DecodeExtra,
+    usize: ColumnIndex<<DB as Database>::Row>,
+    i8: sqlx::Decode<'a, DB>,
+    i16: sqlx::Decode<'a, DB>,
+    i32: sqlx::Decode<'a, DB>,
+    i64: sqlx::Decode<'a, DB>,
+    f32: sqlx::Decode<'a, DB>,
+    f64: sqlx::Decode<'a, DB>,
+    bool: sqlx::Decode<'a, DB>,
+    &'a str: sqlx::Decode<'a, DB>,
+    &'a [u8]: sqlx::Decode<'a, DB>,
+    NaiveDate: sqlx::Decode<'a, DB>,
+    NaiveDateTime: sqlx::Decode<'a, DB>,
+    NaiveTime: sqlx::Decode<'a, DB>,
+    Uuid: sqlx::Decode<'a, DB>,
+{
+    if rows.is_empty() && rows_options.empty_as_nil.unwrap_or(options.empty_as_nil) {
+        laux::lua_pushnil(state);
+        return Ok(1);
+    }
+
+    if rows_options.flat {
+        return push_flat_rows::<DB>(state, rows, rows_options, options);
+    }
+
+    let table = LuaTable::new(state, rows.len(), 0);
+    if rows.is_empty() {
+        return Ok(1);
+    }
+
+    let column_info = column_info::<DB>(rows.first().unwrap(), rows_options.columns.as_deref());
+    for (i, row) in rows.iter().enumerate() {
+        let n = push_row::<DB>(state, row, &column_info, rows_options, options)?;
+        if n != 1 {
+            return Ok(n);
+        }
         table.rawseti(i + 1);
     }
     Ok(1)
 }
 
+extern "C-unwind" fn cancel_connect(state: LuaState) -> i32 {
+    let name = laux::lua_get::<&str>(state, 1);
+    let cancelled = PENDING_CONNECTS
+        .remove(name)
+        .is_some_and(|(_, cancel_tx)| cancel_tx.send(()).is_ok());
+    laux::lua_push(state, cancelled);
+    1
+}
+
 extern "C-unwind" fn find_connection(state: LuaState) -> i32 {
     let name = laux::lua_get::<&str>(state, 1);
     match DATABASE_CONNECTIONSS.get(name) {
         Some(pair) => {
             let l = [
                 lreg!("query", query),
+                lreg!("query_with", query_with),
                 lreg!("transaction", transaction),
+                lreg!("transaction_from_table", transaction_from_table),
+                lreg!("execute_batch", execute_batch),
+                lreg!("delete_in", delete_in),
+                lreg!("update_in", update_in),
+                lreg!("begin", begin),
+                lreg!("prepare_describe", prepare_describe),
+                lreg!("estimate_count", estimate_count),
+                lreg!("export", export),
                 lreg!("close", close),
+                lreg!("cancel_owner", cancel_owner),
+                lreg!("raise_errors", raise_errors),
+                lreg!("server_version", server_version),
+                lreg!("json_path", json_path),
+                lreg!("keyset_page", keyset_page),
                 lreg_null!(),
             ];
             if laux::lua_newuserdata(
@@ -839,13 +6895,164 @@ extern "C-unwind" fn find_connection(state: LuaState) -> i32 {
     1
 }
 
+struct RowStream {
+    response: DatabaseResponse,
+    pos: usize,
+}
+
+extern "C-unwind" fn row_stream_next(state: LuaState) -> i32 {
+    let stream = laux::lua_touserdata::<RowStream>(state, 1).expect("Invalid row stream pointer");
+    laux::lua_checkstack(state, 6, std::ptr::null());
+
+    let pos = stream.pos;
+    let res = match &stream.response {
+        DatabaseResponse::PgRows(rows) => match rows.rows.get(pos) {
+            Some(row) => push_row::<Postgres>(
+                state,
+                row,
+                &column_info::<Postgres>(row, rows.options.columns.as_deref()),
+                &rows.options,
+                &rows.decode,
+            ),
+            None => Ok(0),
+        },
+        DatabaseResponse::MysqlRows(rows) => match rows.rows.get(pos) {
+            Some(row) => push_row::<MySql>(
+                state,
+                row,
+                &column_info::<MySql>(row, rows.options.columns.as_deref()),
+                &rows.options,
+                &rows.decode,
+            ),
+            None => Ok(0),
+        },
+        DatabaseResponse::SqliteRows(rows) => match rows.rows.get(pos) {
+            Some(row) => push_row::<Sqlite>(
+                state,
+                row,
+                &column_info::<Sqlite>(row, rows.options.columns.as_deref()),
+                &rows.options,
+                &rows.decode,
+            ),
+            None => Ok(0),
+        },
+        _ => Ok(0),
+    };
+
+    match res {
+        Ok(0) => {
+            laux::lua_pushnil(state);
+            1
+        }
+        Ok(n) => {
+            stream.pos += 1;
+            n
+        }
+        Err(err) => laux::lua_error(state, err),
+    }
+}
+
+extern "C-unwind" fn row_stream_elapsed_ms(state: LuaState) -> i32 {
+    let stream = laux::lua_touserdata::<RowStream>(state, 1).expect("Invalid row stream pointer");
+    match stream.response.elapsed_ms() {
+        Some(elapsed_ms) => laux::lua_push(state, elapsed_ms),
+        None => laux::lua_pushnil(state),
+    }
+    1
+}
+
+fn push_row_stream(state: LuaState, response: DatabaseResponse) -> i32 {
+    if laux::lua_newuserdata(
+        state,
+        RowStream { response, pos: 0 },
+        cstr!("sqlx_row_stream_metatable"),
+        &[
+            lreg!("next", row_stream_next),
+            lreg!("elapsed_ms", row_stream_elapsed_ms),
+            lreg_null!(),
+        ],
+    )
+    .is_none()
+    {
+        laux::lua_pushnil(state);
+    }
+    1
+}
+
 extern "C-unwind" fn decode(state: LuaState) -> i32 {
     laux::lua_checkstack(state, 6, std::ptr::null());
     let result = lua_into_userdata::<DatabaseResponse>(state, 1);
+    if result.rows_options().is_some_and(|options| options.stream) {
+        return push_row_stream(state, *result);
+    }
+    push_response(state, *result)
+}
+
+extern "C-unwind" fn decode_stream(state: LuaState) -> i32 {
+    let result = lua_into_userdata::<DatabaseResponse>(state, 1);
+    if result.rows_options().is_some() {
+        return push_row_stream(state, *result);
+    }
+    laux::lua_checkstack(state, 6, std::ptr::null());
+    push_response(state, *result)
+}
+
+fn push_elapsed(state: LuaState, n: i32, elapsed_ms: Option<f64>) -> i32 {
+    if n == 1
+        && let Some(elapsed_ms) = elapsed_ms
+        && laux::lua_type(state, -1) == LuaType::Table
+    {
+        LuaTable::from_stack(state, -1).insert("elapsed_ms", elapsed_ms);
+    }
+    n
+}
+
+fn push_truncated(state: LuaState, n: i32, truncated: Option<bool>) {
+    if n == 1
+        && let Some(unordered) = truncated
+        && laux::lua_type(state, -1) == LuaType::Table
+    {
+        let table = LuaTable::from_stack(state, -1);
+        table.insert("truncated", true);
+        if unordered {
+            table.insert(
+                "warning",
+                "max_rows truncated a result without ORDER BY, which rows were kept is nondeterministic",
+            );
+        }
+    }
+}
+
+fn push_affected(state: LuaState, n: i32, affected: Option<u64>) -> i32 {
+    let Some(affected) = affected else {
+        return n;
+    };
+    if n != 1 {
+        return n;
+    }
+    LuaTable::new(state, 0, 2).insert("affected", affected);
+    laux::lua_push(state, "rows");
+    // rows, result, "rows" -> result, "rows", rows
+    unsafe {
+        ffi::lua_rotate(state.as_ptr(), -3, -1);
+        ffi::lua_rawset(state.as_ptr(), -3);
+    }
+    1
+}
+
+fn push_response(state: LuaState, response: DatabaseResponse) -> i32 {
+    let elapsed_ms = response.elapsed_ms();
+    let truncated = response.truncated();
+    let n = push_rows(state, response);
+    push_truncated(state, n, truncated);
+    push_elapsed(state, n, elapsed_ms)
+}
 
-    match *result {
+fn push_rows(state: LuaState, response: DatabaseResponse) -> i32 {
+    match response {
         DatabaseResponse::PgRows(rows) => {
-            return process_rows::<Postgres>(state, &rows)
+            return process_rows::<Postgres>(state, &rows.rows, &rows.options, &rows.decode)
+                .map(|n| push_affected(state, n, rows.affected))
                 .map_err(|e| {
                     push_lua_table!(
                         state,
@@ -856,7 +7063,8 @@ extern "C-unwind" fn decode(state: LuaState) -> i32 {
                 .unwrap_or(1);
         }
         DatabaseResponse::MysqlRows(rows) => {
-            return process_rows::<MySql>(state, &rows)
+            return process_rows::<MySql>(state, &rows.rows, &rows.options, &rows.decode)
+                .map(|n| push_affected(state, n, rows.affected))
                 .map_err(|e| {
                     push_lua_table!(
                         state,
@@ -867,7 +7075,8 @@ extern "C-unwind" fn decode(state: LuaState) -> i32 {
                 .unwrap_or(1);
         }
         DatabaseResponse::SqliteRows(rows) => {
-            return process_rows::<Sqlite>(state, &rows)
+            return process_rows::<Sqlite>(state, &rows.rows, &rows.options, &rows.decode)
+                .map(|n| push_affected(state, n, rows.affected))
                 .map_err(|e| {
                     push_lua_table!(
                         state,
@@ -877,11 +7086,42 @@ extern "C-unwind" fn decode(state: LuaState) -> i32 {
                 })
                 .unwrap_or(1);
         }
-        DatabaseResponse::Transaction => {
-            push_lua_table!(
-                state,
-                "message" => "ok"
-            );
+        DatabaseResponse::Transaction(affected, results) => {
+            let table = LuaTable::new(state, 0, 4);
+            table.insert("message", "ok");
+            table.insert("ok", true);
+            laux::lua_push(state, "affected");
+            let rows = LuaTable::new(state, affected.len(), 0);
+            for count in affected {
+                rows.push(count);
+            }
+            table.insert_from_stack();
+            if results.is_empty() {
+                return 1;
+            }
+            laux::lua_push(state, "results");
+            let labeled = LuaTable::new(state, 0, results.len());
+            for (label, rows) in results {
+                laux::lua_push(state, label.as_str());
+                let n = push_rows(state, rows);
+                if n != 1 {
+                    return n;
+                }
+                labeled.insert_from_stack();
+            }
+            table.insert_from_stack();
+            return 1;
+        }
+        DatabaseResponse::Recycled(closed) => {
+            laux::lua_push(state, closed);
+            return 1;
+        }
+        DatabaseResponse::Exported(count) => {
+            laux::lua_push(state, count);
+            return 1;
+        }
+        DatabaseResponse::Estimated(count) => {
+            laux::lua_push(state, count);
             return 1;
         }
         DatabaseResponse::Connect => {
@@ -893,11 +7133,15 @@ extern "C-unwind" fn decode(state: LuaState) -> i32 {
         }
         DatabaseResponse::Error(err) => match err.as_database_error() {
             Some(db_err) => {
-                push_lua_table!(
-                    state,
-                    "kind" => "DB",
-                    "message" => db_err.message()
-                );
+                let table = LuaTable::new(state, 0, 4);
+                table.insert("kind", "DB");
+                table.insert("message", db_err.message());
+                if let Some(code) = db_err.code() {
+                    table.insert("code", &*code);
+                }
+                if let Some(category) = error_category(db_err) {
+                    table.insert("category", category);
+                }
             }
             None => {
                 push_lua_table!(
@@ -914,21 +7158,252 @@ extern "C-unwind" fn decode(state: LuaState) -> i32 {
                 "message" => err.to_string()
             );
         }
+        DatabaseResponse::Session(transaction) => {
+            return push_transaction_session(state, transaction);
+        }
+        DatabaseResponse::MysqlResultSets(sets) => {
+            let table = LuaTable::new(state, sets.len(), 0);
+            for (i, rows) in sets.iter().enumerate() {
+                match process_rows::<MySql>(state, &rows.rows, &rows.options, &rows.decode) {
+                    Ok(_) => table.rawseti(i + 1),
+                    Err(e) => {
+                        push_lua_table!(
+                            state,
+                            "kind" => "ERROR",
+                            "message" => e
+                        );
+                        return 1;
+                    }
+                }
+            }
+        }
+        DatabaseResponse::Describe(columns) => {
+            let table = LuaTable::new(state, columns.len(), 0);
+            for (i, column) in columns.iter().enumerate() {
+                let entry = LuaTable::new(state, 0, 4);
+                entry.insert("name", column.name.as_str());
+                entry.insert("type", column.db_type.name());
+                entry.insert("type_name", column.type_name.as_str());
+                if let Some(nullable) = column.nullable {
+                    entry.insert("nullable", nullable);
+                }
+                table.rawseti(i + 1);
+            }
+        }
     }
 
     1
 }
 
+fn saturation(in_flight: i64, info: &ConnectionInfo) -> f64 {
+    in_flight as f64 / info.max_connections.max(1) as f64
+}
+
+extern "C-unwind" fn shutdown(_: LuaState) -> i32 {
+    shutdown_databases();
+    0
+}
+
 extern "C-unwind" fn stats(state: LuaState) -> i32 {
+    let detailed: bool = laux::lua_opt(state, 1).unwrap_or(false);
+    let reset: bool = laux::lua_opt(state, 2).unwrap_or(false);
     let table = LuaTable::new(state, 0, DATABASE_CONNECTIONSS.len());
     DATABASE_CONNECTIONSS.iter().for_each(|pair| {
-        table.insert(
-            pair.key().as_str(),
-            pair.value()
-                .counter
+        let conn = pair.value();
+        let pending = conn.counter.load(std::sync::atomic::Ordering::Acquire);
+        if !detailed {
+            table.insert(pair.key().as_str(), pending);
+            return;
+        }
+
+        let peak_pending = if reset {
+            conn.stats
+                .peak_pending
+                .swap(pending, std::sync::atomic::Ordering::AcqRel)
+        } else {
+            conn.stats
+                .peak_pending
+                .load(std::sync::atomic::Ordering::Acquire)
+        };
+
+        laux::lua_push(state, pair.key().as_str());
+        let detail = LuaTable::new(state, 0, 8);
+        detail.insert("pending", pending);
+        detail.insert("saturation", saturation(pending, &conn.info));
+        detail.insert("peak_pending", peak_pending);
+        detail.insert("peak_saturation", saturation(peak_pending, &conn.info));
+        detail.insert(
+            "throttle_delay_ms",
+            conn.stats
+                .throttle_delay_ms
+                .load(std::sync::atomic::Ordering::Acquire),
+        );
+        detail.insert(
+            "throttled",
+            conn.stats
+                .throttled
+                .load(std::sync::atomic::Ordering::Acquire),
+        );
+        detail.insert(
+            "acquire_waiters",
+            conn.stats
+                .acquire_waiters
                 .load(std::sync::atomic::Ordering::Acquire),
         );
+        detail.insert(
+            "active_host",
+            conn.stats.active_host.lock().unwrap().as_str(),
+        );
+        if let Some(version) = conn.stats.server_version.lock().unwrap().as_ref() {
+            detail.insert("server_version", version.text.as_str());
+        }
+        // running averages of what `set_send_stats` measured
+        let mut send = conn.stats.send.lock().unwrap();
+        if send.responses > 0 {
+            let responses = send.responses as f64;
+            detail.insert("sent_responses", send.responses);
+            detail.insert("avg_send_rows", send.rows as f64 / responses);
+            detail.insert("avg_send_bytes", send.bytes as f64 / responses);
+            detail.insert(
+                "avg_send_us",
+                send.elapsed.as_secs_f64() * 1_000_000.0 / responses,
+            );
+        }
+        if reset {
+            *send = SendStats::default();
+        }
+        drop(send);
+        table.insert_from_stack();
+    });
+    1
+}
+
+extern "C-unwind" fn health(state: LuaState) -> i32 {
+    let mut healthy = true;
+    let table = LuaTable::new(state, 0, 2);
+    laux::lua_push(state, "details");
+    let details = LuaTable::new(state, 0, DATABASE_CONNECTIONSS.len());
+    DATABASE_CONNECTIONSS.iter().for_each(|pair| {
+        let conn = pair.value();
+        let ok = !conn
+            .stats
+            .unhealthy
+            .load(std::sync::atomic::Ordering::Acquire);
+        healthy &= ok;
+
+        laux::lua_push(state, pair.key().as_str());
+        let detail = LuaTable::new(state, 0, 3);
+        detail.insert("healthy", ok);
+        detail.insert(
+            "pending",
+            conn.counter.load(std::sync::atomic::Ordering::Acquire),
+        );
+        if let Some(err) = conn.stats.last_error.lock().unwrap().as_deref() {
+            detail.insert("last_error", err);
+        }
+        details.insert_from_stack();
+    });
+    table.insert_from_stack();
+    table.insert("healthy", healthy);
+    1
+}
+
+extern "C-unwind" fn recycle(state: LuaState) -> i32 {
+    let owner = laux::lua_get(state, 1);
+    let session = laux::lua_get(state, 2);
+    let name = laux::lua_get::<&str>(state, 3);
+    let Some(pair) = DATABASE_CONNECTIONSS.get(name) else {
+        return push_request_error(state, false, format!("no database connection '{}'", name));
+    };
+
+    let conn = pair.value();
+    let raise_errors = raises(conn, session);
+    if let Err(err) = check_inflight(conn) {
+        drop(pair);
+        return push_request_error_kind(state, raise_errors, "OVERLOADED", err);
+    }
+    let res = conn
+        .tx
+        .try_send(DatabaseRequest::Recycle(owner, session))
+        .map(|_| {
+            conn.counter
+                .fetch_add(1, std::sync::atomic::Ordering::Release)
+        });
+    // released before a raise can unwind past the guard
+    drop(pair);
+    match res {
+        Ok(_) => push_accepted(state, session),
+        Err(err) => push_request_error(state, raise_errors, err),
+    }
+}
+
+extern "C-unwind" fn set_send_stats(state: LuaState) -> i32 {
+    let name = laux::lua_get::<&str>(state, 1);
+    let enabled: bool = laux::lua_get(state, 2);
+    let found = DATABASE_CONNECTIONSS.get(name).map(|pair| {
+        pair.value()
+            .stats
+            .measure_send
+            .store(enabled, std::sync::atomic::Ordering::Relaxed)
+    });
+    laux::lua_push(state, found.is_some());
+    1
+}
+
+extern "C-unwind" fn set_trace(state: LuaState) -> i32 {
+    let name = laux::lua_get::<&str>(state, 1);
+    let enabled: bool = laux::lua_get(state, 2);
+    let found = DATABASE_CONNECTIONSS.get(name).map(|pair| {
+        pair.value()
+            .stats
+            .trace
+            .store(enabled, std::sync::atomic::Ordering::Relaxed)
     });
+    laux::lua_push(state, found.is_some());
+    1
+}
+
+extern "C-unwind" fn describe_connection(state: LuaState) -> i32 {
+    let name = laux::lua_get::<&str>(state, 1);
+    let Some(pair) = DATABASE_CONNECTIONSS.get(name) else {
+        laux::lua_pushnil(state);
+        return 1;
+    };
+
+    let info = &pair.value().info;
+    let active_host = pair.value().stats.active_host.lock().unwrap().clone();
+    let (host, port) = active_host
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .map_or((active_host.as_str(), None), |(host, port)| {
+            (host, Some(port))
+        });
+
+    let table = LuaTable::new(state, 0, 14);
+    table.insert("backend", info.backend);
+    table.insert("host", host);
+    if let Some(port) = port {
+        table.insert("port", port);
+    }
+    table.insert("database", info.database.as_str());
+    table.insert("username", info.username.as_str());
+    table.insert("connect_timeout_ms", info.connect_timeout_ms);
+    table.insert("max_connections", info.max_connections);
+    table.insert("min_connections", info.min_connections);
+    table.insert("acquire_timeout_ms", info.acquire_timeout_ms);
+    if let Some(idle_timeout_ms) = info.idle_timeout_ms {
+        table.insert("idle_timeout_ms", idle_timeout_ms);
+    }
+    if let Some(max_lifetime_ms) = info.max_lifetime_ms {
+        table.insert("max_lifetime_ms", max_lifetime_ms);
+    }
+
+    laux::lua_push(state, "hosts");
+    let hosts = LuaTable::new(state, info.hosts.len(), 0);
+    for host in info.hosts.iter() {
+        hosts.push(host.as_str());
+    }
+    table.insert_from_stack();
     1
 }
 
@@ -939,9 +7414,30 @@ pub extern "C-unwind" fn luaopen_rust_sqlx(state: LuaState) -> i32 {
     let l = [
         lreg!("connect", connect),
         lreg!("find_connection", find_connection),
+        lreg!("cancel_connect", cancel_connect),
         lreg!("decode", decode),
+        lreg!("decode_stream", decode_stream),
         lreg!("stats", stats),
+        lreg!("health", health),
+        lreg!("shutdown", shutdown),
+        lreg!("describe_connection", describe_connection),
+        lreg!("recycle", recycle),
+        lreg!("set_trace", set_trace),
+        lreg!("set_send_stats", set_send_stats),
         lreg!("make_transaction", make_transaction),
+        lreg!("json", bind_json),
+        lreg!("jsonb", bind_jsonb),
+        lreg!("interval", bind_interval),
+        lreg!("interval_array", bind_interval_array),
+        lreg!("decimal", bind_decimal),
+        lreg!("time", bind_time),
+        lreg!("maybe", bind_maybe),
+        lreg!("tsquery", bind_tsquery),
+        lreg!("char", bind_char),
+        lreg!("uuid_bin", bind_uuid_bin),
+        lreg!("record", bind_record),
+        lreg!("bytea_array", bind_bytea_array),
+        lreg!("bool_array", bind_bool_array),
         lreg_null!(),
     ];
 
@@ -949,3 +7445,452 @@ pub extern "C-unwind" fn luaopen_rust_sqlx(state: LuaState) -> i32 {
 
     1
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wkb(code: u32, coords: &[f64]) -> Vec<u8> {
+        let mut buf = vec![1];
+        buf.extend_from_slice(&code.to_le_bytes());
+        for c in coords {
+            buf.extend_from_slice(&c.to_le_bytes());
+        }
+        buf
+    }
+
+    fn collection(members: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec![1];
+        buf.extend_from_slice(&7u32.to_le_bytes());
+        buf.extend_from_slice(&(members.len() as u32).to_le_bytes());
+        for member in members {
+            buf.extend_from_slice(member);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_wkb_points() {
+        assert_eq!(wkb_to_wkt(&wkb(1, &[1.0, 2.5])), "POINT(1 2.5)");
+        assert_eq!(wkb_to_wkt(&wkb(1001, &[1.0, 2.0, 3.0])), "POINT Z (1 2 3)");
+        assert_eq!(wkb_to_wkt(&wkb(2001, &[1.0, 2.0, 4.0])), "POINT M (1 2 4)");
+        assert_eq!(
+            wkb_to_wkt(&wkb(3001, &[1.0, 2.0, 3.0, 4.0])),
+            "POINT ZM (1 2 3 4)"
+        );
+
+        let mut big = vec![0];
+        big.extend_from_slice(&1u32.to_be_bytes());
+        big.extend_from_slice(&1.0f64.to_be_bytes());
+        big.extend_from_slice(&2.0f64.to_be_bytes());
+        assert_eq!(wkb_to_wkt(&big), "POINT(1 2)");
+    }
+
+    #[test]
+    fn test_ewkb() {
+        let mut point = vec![1];
+        point.extend_from_slice(&0x2000_0001u32.to_le_bytes());
+        point.extend_from_slice(&4326u32.to_le_bytes());
+        point.extend_from_slice(&1.0f64.to_le_bytes());
+        point.extend_from_slice(&2.0f64.to_le_bytes());
+        assert_eq!(wkb_to_wkt(&point), "POINT(1 2)");
+        assert_eq!(ewkb_srid(&point), 4326);
+        assert_eq!(ewkb_srid(&wkb(1, &[1.0, 2.0])), 0);
+
+        let z = wkb(0x8000_0001, &[1.0, 2.0, 3.0]);
+        assert_eq!(wkb_to_wkt(&z), "POINT Z (1 2 3)");
+    }
+
+    #[test]
+    fn test_wkb_empty() {
+        assert_eq!(wkb_to_wkt(&wkb(1, &[f64::NAN, f64::NAN])), "POINT EMPTY");
+        let mut line = wkb(2, &[]);
+        line.extend_from_slice(&0u32.to_le_bytes());
+        assert_eq!(wkb_to_wkt(&line), "LINESTRING EMPTY");
+        assert_eq!(wkb_to_wkt(&collection(&[])), "GEOMETRYCOLLECTION EMPTY");
+    }
+
+    #[test]
+    fn test_wkb_nested() {
+        let mut line = wkb(2, &[]);
+        line.extend_from_slice(&2u32.to_le_bytes());
+        for c in [0.0f64, 0.0, 1.0, 1.0] {
+            line.extend_from_slice(&c.to_le_bytes());
+        }
+        let mut multi = wkb(4, &[]);
+        multi.extend_from_slice(&2u32.to_le_bytes());
+        multi.extend_from_slice(&wkb(1, &[1.0, 2.0]));
+        multi.extend_from_slice(&wkb(1, &[3.0, 4.0]));
+        let inner = collection(&[wkb(1, &[1.0, 2.0]), line]);
+        assert_eq!(
+            wkb_to_wkt(&collection(&[inner, multi])),
+            "GEOMETRYCOLLECTION(GEOMETRYCOLLECTION(POINT(1 2),LINESTRING(0 0,1 1)),MULTIPOINT((1 2),(3 4)))"
+        );
+
+        let mut deep = wkb(1, &[1.0, 2.0]);
+        for _ in 0..WKB_MAX_DEPTH {
+            deep = collection(&[deep]);
+        }
+        assert!(wkb_to_wkt(&deep).starts_with("WKB:0107000000"));
+
+        // a hostile value nesting far past the limit must not overflow the stack
+        let mut hostile = Vec::new();
+        for _ in 0..100_000 {
+            hostile.extend_from_slice(&collection(&[])[..5]);
+            hostile.extend_from_slice(&1u32.to_le_bytes());
+        }
+        hostile.extend_from_slice(&wkb(1, &[1.0, 2.0]));
+        assert!(wkb_to_wkt(&hostile).starts_with("WKB:"));
+    }
+
+    #[test]
+    fn test_wkb_truncated() {
+        let point = wkb(1, &[1.0, 2.0]);
+        assert_eq!(wkb_to_wkt(&point[..12]), "WKB:0101000000000000000000F0");
+        assert!(wkb_to_wkt(&[1, 99, 0, 0, 0]).starts_with("WKB:"));
+        assert!(wkb_to_wkt(&[]).starts_with("WKB:"));
+    }
+
+    #[test]
+    fn test_count_placeholders() {
+        assert_eq!(
+            count_placeholders("SELECT * FROM t WHERE a = ? AND b = ?", false),
+            2
+        );
+        assert_eq!(count_placeholders("SELECT $2, $1, $10", false), 10);
+        assert_eq!(count_placeholders("SELECT 1", false), 0);
+        assert_eq!(
+            count_placeholders(
+                "SELECT '?', \"?\", `?`, 'it''s ?' FROM t WHERE a = ?",
+                false
+            ),
+            1
+        );
+        assert_eq!(count_placeholders("SELECT ? -- ?\n, ? /* ? */", false), 2);
+        assert_eq!(count_placeholders("SELECT a$b, ?", false), 1);
+    }
+
+    #[test]
+    fn test_count_placeholders_dollar_quotes() {
+        assert_eq!(count_placeholders("SELECT $$ ? $1 $$, ?", false), 1);
+        assert_eq!(
+            count_placeholders("SELECT $fn$ $$ $2 $fn$ WHERE a = $1", false),
+            1
+        );
+        assert_eq!(count_placeholders("SELECT $tag$ ? never closed", false), 0);
+    }
+
+    #[test]
+    fn test_count_placeholders_escapes() {
+        // Postgres: only E'...' strings take backslash escapes
+        assert_eq!(count_placeholders(r"SELECT E'\'', ?", false), 1);
+        assert_eq!(count_placeholders(r"SELECT e'\'', ?", false), 1);
+        assert_eq!(count_placeholders(r"SELECT '\'', ?", false), 0);
+        assert_eq!(count_placeholders(r"SELECT typE'\'', ?", false), 0);
+        // MySQL: every quoted string does
+        assert_eq!(count_placeholders(r"SELECT '\'', ?", true), 1);
+        assert_eq!(count_placeholders(r#"SELECT "\"?", ?"#, true), 1);
+    }
+
+    fn interval(months: i32, days: i32, microseconds: i64) -> PgInterval {
+        PgInterval {
+            months,
+            days,
+            microseconds,
+        }
+    }
+
+    #[test]
+    fn test_format_interval() {
+        assert_eq!(format_interval(&interval(0, 0, 0)), "00:00:00");
+        assert_eq!(format_interval(&interval(1, 1, 0)), "1 mon 1 day");
+        assert_eq!(format_interval(&interval(12, 0, 0)), "1 year");
+        assert_eq!(
+            format_interval(&interval(14, 3, 14_706_500_000)),
+            "1 year 2 mons 3 days 04:05:06.5"
+        );
+        assert_eq!(format_interval(&interval(0, 0, 1)), "00:00:00.000001");
+        assert_eq!(format_interval(&interval(0, 0, -90_000_000)), "-00:01:30");
+        assert_eq!(
+            format_interval(&interval(0, -1, 3_600_000_000)),
+            "-1 days +01:00:00"
+        );
+        assert_eq!(
+            format_interval(&interval(-14, 2, 0)),
+            "-1 years -2 mons +2 days"
+        );
+    }
+
+    fn tsvector(entries: &[(&str, &[u16])]) -> Vec<u8> {
+        let mut buf = (entries.len() as i32).to_be_bytes().to_vec();
+        for (lexeme, positions) in entries {
+            buf.extend_from_slice(lexeme.as_bytes());
+            buf.push(0);
+            buf.extend_from_slice(&(positions.len() as u16).to_be_bytes());
+            for position in *positions {
+                buf.extend_from_slice(&position.to_be_bytes());
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn test_tsvector() {
+        let bytes = tsvector(&[
+            ("fat", &[2 | 3 << 14, 4]),
+            ("it's", &[]),
+            ("rat", &[3 | 1 << 14]),
+        ]);
+        assert_eq!(
+            tsvector_to_text(&bytes).as_deref(),
+            Some("'fat':2A,4 'it''s' 'rat':3C")
+        );
+        assert_eq!(tsvector_to_text(&tsvector(&[])).as_deref(), Some(""));
+        assert_eq!(tsvector_to_text(&bytes[..bytes.len() - 1]), None);
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(tsvector_to_text(&trailing), None);
+    }
+
+    fn ts_val(buf: &mut Vec<u8>, lexeme: &str, weight: u8, prefix: bool) {
+        buf.extend_from_slice(&[TS_VAL, weight, prefix as u8]);
+        buf.extend_from_slice(lexeme.as_bytes());
+        buf.push(0);
+    }
+
+    fn tsquery(count: i32) -> Vec<u8> {
+        count.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_tsquery() {
+        // prefix order, right operand before the left
+        let mut bytes = tsquery(5);
+        bytes.extend_from_slice(&[TS_OPR, TS_AND, TS_OPR, TS_OR]);
+        ts_val(&mut bytes, "cat", 0, false);
+        ts_val(&mut bytes, "rat", 0, false);
+        ts_val(&mut bytes, "fat", 0, false);
+        assert_eq!(
+            tsquery_to_text(&bytes).as_deref(),
+            Some("'fat' & ( 'rat' | 'cat' )")
+        );
+
+        let mut bytes = tsquery(4);
+        bytes.extend_from_slice(&[TS_OPR, TS_PHRASE, 0, 3, TS_OPR, TS_NOT]);
+        ts_val(&mut bytes, "b", 0, false);
+        ts_val(&mut bytes, "a", 8 | 1, true);
+        assert_eq!(tsquery_to_text(&bytes).as_deref(), Some("'a':*AD <3> !'b'"));
+
+        assert_eq!(tsquery_to_text(&tsquery(0)).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_tsquery_invalid() {
+        let mut bytes = tsquery(2);
+        ts_val(&mut bytes, "a", 0, false);
+        assert_eq!(tsquery_to_text(&bytes), None);
+
+        let mut bytes = tsquery(3);
+        bytes.extend_from_slice(&[TS_OPR, 9]);
+        ts_val(&mut bytes, "a", 0, false);
+        ts_val(&mut bytes, "b", 0, false);
+        assert_eq!(tsquery_to_text(&bytes), None);
+
+        let mut bytes = tsquery(TS_MAX_DEPTH as i32 + 1);
+        for _ in 0..TS_MAX_DEPTH {
+            bytes.extend_from_slice(&[TS_OPR, TS_NOT]);
+        }
+        ts_val(&mut bytes, "a", 0, false);
+        assert_eq!(tsquery_to_text(&bytes), None);
+    }
+
+    fn sized(buf: &mut Vec<u8>, value: Option<&[u8]>) {
+        match value {
+            Some(value) => {
+                buf.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                buf.extend_from_slice(value);
+            }
+            None => buf.extend_from_slice(&(-1i32).to_be_bytes()),
+        }
+    }
+
+    fn composite(fields: &[(u32, Option<&[u8]>)]) -> Vec<u8> {
+        let mut buf = (fields.len() as i32).to_be_bytes().to_vec();
+        for (oid, value) in fields {
+            buf.extend_from_slice(&oid.to_be_bytes());
+            sized(&mut buf, *value);
+        }
+        buf
+    }
+
+    fn composite_array(dims: &[i32], elements: &[Option<&[u8]>]) -> Vec<u8> {
+        let mut buf = (dims.len() as i32).to_be_bytes().to_vec();
+        // has-NULLs flag and element type OID
+        buf.extend_from_slice(&[0; 8]);
+        for len in dims {
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend_from_slice(&1i32.to_be_bytes());
+        }
+        for element in elements {
+            sized(&mut buf, *element);
+        }
+        buf
+    }
+
+    fn composite_json(fields: Vec<CompositeField>) -> Vec<serde_json::Value> {
+        fields.into_iter().map(CompositeField::json).collect()
+    }
+
+    #[test]
+    fn test_composite() {
+        let bytes = composite(&[
+            (23, Some(&7i32.to_be_bytes())),
+            (25, None),
+            (25, Some(b"it's")),
+            (16, Some(&[1])),
+        ]);
+        assert_eq!(
+            parse_composite(&bytes).map(composite_json),
+            Some(vec![
+                serde_json::json!(7),
+                serde_json::Value::Null,
+                serde_json::json!("it's"),
+                serde_json::json!(true),
+            ])
+        );
+        // an int4[] field is left as its raw bytes
+        let inner = composite_array(&[2], &[Some(&1i32.to_be_bytes()), None]);
+        let bytes = composite(&[(1007, Some(&inner))]);
+        let fields = parse_composite(&bytes).unwrap();
+        assert!(
+            matches!(fields.as_slice(), [CompositeField::Bytes(raw)] if *raw == inner.as_slice())
+        );
+        assert_eq!(parse_composite(&composite(&[])).map(|f| f.len()), Some(0));
+    }
+
+    #[test]
+    fn test_composite_invalid() {
+        let bytes = composite(&[(23, Some(&7i32.to_be_bytes())), (25, Some(b"abc"))]);
+        for len in 0..bytes.len() {
+            assert!(
+                parse_composite(&bytes[..len]).is_none(),
+                "truncated at {}",
+                len
+            );
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(parse_composite(&trailing).is_none());
+        // a count far past the input
+        assert!(parse_composite(&i32::MAX.to_be_bytes()).is_none());
+        assert!(parse_composite(&(-1i32).to_be_bytes()).is_none());
+    }
+
+    #[test]
+    fn test_composite_array() {
+        let first = composite(&[(23, Some(&1i32.to_be_bytes()))]);
+        let second = composite(&[(23, None)]);
+        // a 2x2 array is flattened in row order, NULL elements kept in place
+        let bytes = composite_array(&[2, 2], &[Some(&first), None, Some(&second), Some(&first)]);
+        let items: Vec<Option<Vec<serde_json::Value>>> = parse_composite_array(&bytes)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.map(composite_json))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                Some(vec![serde_json::json!(1)]),
+                None,
+                Some(vec![serde_json::Value::Null]),
+                Some(vec![serde_json::json!(1)]),
+            ]
+        );
+        // an empty array has no dimensions
+        assert_eq!(
+            parse_composite_array(&composite_array(&[], &[])).map(|items| items.len()),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_composite_array_invalid() {
+        let element = composite(&[(23, Some(&1i32.to_be_bytes()))]);
+        let bytes = composite_array(&[2], &[Some(&element), None]);
+        for len in 0..bytes.len() {
+            assert!(
+                parse_composite_array(&bytes[..len]).is_none(),
+                "truncated at {}",
+                len
+            );
+        }
+        // an element that is not a valid composite fails the whole array
+        let bytes = composite_array(&[1], &[Some(&element[..element.len() - 1])]);
+        assert!(parse_composite_array(&bytes).is_none());
+        // dimensions that multiply past usize, or claim more elements than are present
+        assert!(
+            parse_composite_array(&composite_array(&[i32::MAX, i32::MAX, i32::MAX], &[])).is_none()
+        );
+        assert!(parse_composite_array(&composite_array(&[3], &[None, None])).is_none());
+    }
+
+    #[test]
+    fn test_server_version() {
+        let version = ServerVersion::parse("8.0.33-0ubuntu0.22.04.2").unwrap();
+        assert_eq!((version.text.as_str(), version.number), ("8.0.33", 80033));
+        let version = ServerVersion::parse("16.2 (Debian 16.2-1.pgdg120+2)").unwrap();
+        assert_eq!((version.text.as_str(), version.number), ("16.2", 160200));
+        let version = ServerVersion::parse("3.45.1").unwrap();
+        assert_eq!(version.number, 34501);
+        let version = ServerVersion::parse("10.").unwrap();
+        assert_eq!((version.text.as_str(), version.number), ("10", 100000));
+        assert!(ServerVersion::parse("PostgreSQL 16").is_none());
+        assert!(ServerVersion::parse("").is_none());
+    }
+
+    #[test]
+    fn test_has_order_by() {
+        assert!(has_order_by("SELECT * FROM t ORDER BY id"));
+        assert!(has_order_by("select * from t order\n\tby id"));
+        assert!(has_order_by(
+            "SELECT * FROM (SELECT * FROM t ORDER BY id) x"
+        ));
+        assert!(!has_order_by("SELECT * FROM t"));
+        assert!(!has_order_by("SELECT border, by FROM t"));
+        assert!(!has_order_by("SELECT * FROM t ORDER"));
+    }
+
+    #[test]
+    fn test_is_shareable_read() {
+        assert!(is_shareable_read("SELECT * FROM t WHERE id = ?"));
+        assert!(is_shareable_read("/* tag */ select now()"));
+        assert!(is_shareable_read("SELECT random_name, uuid FROM t"));
+        assert!(!is_shareable_read("UPDATE t SET a = 1"));
+        assert!(!is_shareable_read("SELECTED"));
+        assert!(!is_shareable_read("SELECT * FROM t FOR UPDATE"));
+        assert!(!is_shareable_read("SELECT * FROM t FOR NO KEY UPDATE"));
+        assert!(!is_shareable_read("SELECT * FROM t LOCK IN SHARE MODE"));
+        assert!(!is_shareable_read("SELECT a INTO @x FROM t"));
+        assert!(!is_shareable_read("SELECT RANDOM()"));
+        assert!(!is_shareable_read("SELECT uuid ()"));
+        assert!(!is_shareable_read("SELECT pg_advisory_xact_lock(1)"));
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expand_env("a${PATH}b").unwrap(), format!("a{}b", path));
+        assert_eq!(
+            expand_env("postgres://u@h/db").unwrap(),
+            "postgres://u@h/db"
+        );
+        assert_eq!(
+            expand_env("x${LRUST_TEST_UNSET_VARIABLE}").unwrap_err(),
+            "environment variable 'LRUST_TEST_UNSET_VARIABLE' is not set"
+        );
+        assert_eq!(
+            expand_env("secret ${PATH").unwrap_err(),
+            "unterminated '${' in connection string"
+        );
+    }
+}
@@ -1,5 +1,8 @@
 use crate::lua_json::{encode_table, JsonOptions};
-use crate::{moon_log, moon_send, LOG_LEVEL_ERROR, LOG_LEVEL_INFO};
+use crate::{
+    db_shutdown_receiver, db_shutting_down, moon_log, moon_send, shutdown_databases,
+    LOG_LEVEL_ERROR, LOG_LEVEL_INFO,
+};
 use dashmap::DashMap;
 use futures::TryFutureExt;
 use lazy_static::lazy_static;
@@ -8,7 +11,7 @@ use lib_lua::laux::{lua_into_userdata, LuaArgs, LuaNil, LuaState, LuaTable, LuaV
 use lib_lua::luaL_newlib;
 use lib_lua::{self, cstr, ffi, laux, lreg, lreg_null, push_lua_table};
 
-use std::sync::atomic::AtomicI64;
+use std::sync::atomic::{AtomicI64, AtomicU64};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
@@ -142,6 +145,15 @@ enum DatabaseRequest {
 struct DatabaseConnection {
     tx: mpsc::Sender<DatabaseRequest>,
     counter: Arc<AtomicI64>,
+    stats: Arc<ConnectionStats>,
+}
+
+#[derive(Default)]
+struct ConnectionStats {
+    /// Number of tiberius clients owned by the handler
+    clients_total: AtomicU64,
+    /// Clients currently executing a request
+    clients_busy: AtomicU64,
 }
 
 enum DatabaseResponse {
@@ -198,6 +210,19 @@ async fn handle_result(
                 moon_send(protocol_type, owner, session, DatabaseResponse::Error(err));
                 counter.fetch_sub(1, std::sync::atomic::Ordering::Release);
                 false
+            } else if db_shutting_down() {
+                // an unreachable database would otherwise keep the drain retrying forever
+                moon_log(
+                    owner,
+                    LOG_LEVEL_ERROR,
+                    format!(
+                        "Database '{}' error: '{:?}'. Dropped, shutting down.",
+                        config_str,
+                        err.to_string()
+                    ),
+                );
+                counter.fetch_sub(1, std::sync::atomic::Ordering::Release);
+                false
             } else {
                 if *failed_times > 0 {
                     moon_log(
@@ -224,9 +249,34 @@ async fn database_handler(
     mut rx: mpsc::Receiver<DatabaseRequest>,
     config_str: &str,
     counter: Arc<AtomicI64>,
+    stats: Arc<ConnectionStats>,
 ) {
-    while let Some(op) = rx.recv().await {
+    stats
+        .clients_total
+        .store(1, std::sync::atomic::Ordering::Release);
+    let mut shutdown = db_shutdown_receiver();
+    let mut closing = false;
+    loop {
+        let op = if closing {
+            rx.recv().await
+        } else {
+            tokio::select! {
+                op = rx.recv() => op,
+                // stop accepting requests, the ones already queued still run before the client closes
+                _ = shutdown.wait_for(|stop| *stop) => {
+                    rx.close();
+                    closing = true;
+                    continue;
+                }
+            }
+        };
+        let Some(op) = op else {
+            break;
+        };
         let mut failed_times = 0;
+        stats
+            .clients_busy
+            .fetch_add(1, std::sync::atomic::Ordering::Release);
         match &op {
             DatabaseRequest::Query(owner, session, query_op) => {
                 while handle_result(
@@ -268,10 +318,20 @@ async fn database_handler(
                 {}
             }
             DatabaseRequest::Close() => {
+                stats
+                    .clients_busy
+                    .fetch_sub(1, std::sync::atomic::Ordering::Release);
                 break;
             }
         }
+        stats
+            .clients_busy
+            .fetch_sub(1, std::sync::atomic::Ordering::Release);
     }
+    let _ = pool.client.close().await;
+    stats
+        .clients_total
+        .store(0, std::sync::atomic::Ordering::Release);
 }
 
 extern "C-unwind" fn connect(state: LuaState) -> i32 {
@@ -283,6 +343,15 @@ extern "C-unwind" fn connect(state: LuaState) -> i32 {
     let name: &str = laux::lua_get(state, 5);
     let connect_timeout: u64 = laux::lua_opt(state, 6).unwrap_or(30000);
 
+    if db_shutting_down() {
+        push_lua_table!(
+            state,
+            "kind" => "ERROR",
+            "message" => "connect refused: databases are shutting down"
+        );
+        return 1;
+    }
+
     let config_str = config_str.to_string();
     let name = name.to_string();
 
@@ -293,15 +362,17 @@ extern "C-unwind" fn connect(state: LuaState) -> i32 {
             Ok(pool) => {
                 let (tx, rx) = mpsc::channel(100);
                 let counter = Arc::new(AtomicI64::new(0));
+                let stats = Arc::new(ConnectionStats::default());
                 DATABASE_CONNECTIONS.insert(
                     name.clone(),
                     DatabaseConnection {
                         tx: tx.clone(),
                         counter: counter.clone(),
+                        stats: stats.clone(),
                     },
                 );
                 moon_send(protocol_type, owner, session, DatabaseResponse::Connect);
-                database_handler(protocol_type, pool, rx, &config_str, counter).await;
+                database_handler(protocol_type, pool, rx, &config_str, counter, stats).await;
             }
             Err(err) => {
                 println!("SQL Server connection failed: {}", err);
@@ -686,18 +757,41 @@ extern "C-unwind" fn decode(state: LuaState) -> i32 {
 }
 
 extern "C-unwind" fn stats(state: LuaState) -> i32 {
+    let detailed: bool = laux::lua_opt(state, 1).unwrap_or(false);
     let table = LuaTable::new(state, 0, DATABASE_CONNECTIONS.len());
     DATABASE_CONNECTIONS.iter().for_each(|pair| {
-        table.rawset(
-            pair.key().as_str(),
-            pair.value()
-                .counter
+        let conn = pair.value();
+        let pending = conn.counter.load(std::sync::atomic::Ordering::Acquire);
+        if !detailed {
+            table.insert(pair.key().as_str(), pending);
+            return;
+        }
+
+        laux::lua_push(state, pair.key().as_str());
+        let detail = LuaTable::new(state, 0, 3);
+        detail.insert("pending", pending);
+        detail.insert(
+            "clients_total",
+            conn.stats
+                .clients_total
                 .load(std::sync::atomic::Ordering::Acquire),
         );
+        detail.insert(
+            "clients_busy",
+            conn.stats
+                .clients_busy
+                .load(std::sync::atomic::Ordering::Acquire),
+        );
+        table.insert_from_stack();
     });
     1
 }
 
+extern "C-unwind" fn shutdown(_: LuaState) -> i32 {
+    shutdown_databases();
+    0
+}
+
 #[cfg(feature = "tiberius")]
 #[no_mangle]
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
@@ -707,6 +801,7 @@ pub extern "C-unwind" fn luaopen_rust_tiberius(state: LuaState) -> i32 {
         lreg!("find_connection", find_connection),
         lreg!("decode", decode),
         lreg!("stats", stats),
+        lreg!("shutdown", shutdown),
         lreg!("make_transaction", make_transaction),
         lreg_null!(),
     ];
@@ -24,7 +24,11 @@ local M = {}
 -- @param timeout: Connection timeout in milliseconds (default: 5000)
 -- @return session_id or error table
 function M.connect(config_string, name, timeout)
-    local res = moon.wait(c.connect(protocol_type, moon.id, moon.next_sequence(), config_string, name, timeout))
+    local res = c.connect(protocol_type, moon.id, moon.next_sequence(), config_string, name, timeout)
+    -- refused right away as an error table while databases are shutting down
+    if type(res) ~= "table" then
+        res = moon.wait(res)
+    end
     if res.kind then
         error(string.format("connect database failed: %s", res.message))
     end
@@ -110,9 +114,18 @@ function M:close()
 end
 
 -- Get connection statistics
+-- @param detailed: when true each connection maps to {pending, clients_total, clients_busy}
+--   instead of the bare pending counter
 -- @return table with connection statistics
-function M.stats()
-    return c.stats()
+function M.stats(detailed)
+    return c.stats(detailed)
+end
+
+-- Shut down every database handler of the process (SQL Server and sqlx alike)
+-- Handlers stop accepting requests, finish the ones already queued, then close their connections.
+-- Requests made afterwards fail with a channel closed error.
+function M.shutdown()
+    c.shutdown()
 end
 
 -- Helper function to build connection string
@@ -144,6 +144,35 @@ moon.async(function()
     res = db:query("SELECT * FROM type_test WHERE col_int >= 1000;")
     print_r(res)
 
+    -- UNION 首个分支为 NULL 字面量的列, 按值的类型解码
+    print("\n===== UNION NULL 字面量列 =====")
+    res = db:query("SELECT NULL AS v UNION ALL SELECT 42 UNION ALL SELECT 'text'")
+    print_r(res)
+    assert(#res == 3 and res[1].v == nil and res[2].v == "42" and res[3].v == "text")
+    res = db:query("SELECT NULL AS v UNION ALL SELECT 42")
+    assert(res[1].v == nil and res[2].v == 42)
+
+    -- 按列指定编码, latin1 字节转为 UTF-8
+    print("\n===== 按列字符集解码 =====")
+    res = db:query_with({ encodings = { legacy = "latin1" } }, "SELECT UNHEX('436166E9') AS legacy, 'plain' AS other")
+    print_r(res)
+    assert(res[1].legacy == "Café" and res[1].other == "plain", res.message)
+    -- 导出与查询共用同一解码路径, encodings 同样生效
+    local export_path = "sqlx_export_encodings.csv"
+    res = db:export_with({ encodings = { legacy = "latin1" } }, "SELECT UNHEX('436166E9') AS legacy", export_path, "csv")
+    assert(res == 1, res.message)
+    local file = assert(io.open(export_path, "rb"))
+    local content = file:read("a")
+    file:close()
+    os.remove(export_path)
+    assert(content == "legacy\nCafé\n", content)
+
+    -- 非 CALL 语句的唯一结果集不应被当作末尾状态包丢弃
+    print("\n===== 多结果集 =====")
+    res = db:query_with({ result_sets = true }, "SELECT 1 AS a")
+    print_r(res)
+    assert(#res == 1 and res[1][1].a == 1, res.message)
+
     -- 统计信息
     print("\n===== SQLX 统计信息 =====")
     print_r(sqlx.stats())